@@ -0,0 +1,30 @@
+use ocl_include::*;
+use std::path::Path;
+
+fn main() {
+    let parser = Parser::builder()
+        .add_source(
+            source::Fs::builder()
+                .include_dir(Path::new("./examples"))
+                .unwrap()
+                .build(),
+        )
+        .build();
+    let node = parser.parse(Path::new("main.c")).unwrap();
+
+    // Print the include tree with number of non-empty lines in each file
+    node.visit(|node, depth| {
+        let count = node.lines().filter(|l| !l.trim().is_empty()).count();
+        println!("{}{:?} ({} lines)", "  ".repeat(depth), node.name(), count);
+    });
+
+    // Print where the headers are included
+    for (child, line) in node.children() {
+        println!(
+            "{:?} includes {:?} at line {}",
+            node.name(),
+            child.name(),
+            line + 1
+        );
+    }
+}
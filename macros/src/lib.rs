@@ -0,0 +1,133 @@
+//! Compile-time version of [ocl-include](https://docs.rs/ocl-include).
+//!
+//! ```ignore
+//! use ocl_include::StaticCode;
+//! use ocl_include_macros::include_cl;
+//!
+//! static KERNEL: StaticCode = include_cl!(
+//!     "kernels/main.cl",
+//!     include_dir = "kernels/include",
+//!     define = "USE_DOUBLE",
+//!     define = "WG_SIZE=64",
+//! );
+//! ```
+
+use ocl_include::{source, Parser};
+use proc_macro::TokenStream;
+use proc_macro2::{Literal, Span};
+use quote::quote;
+use std::{env, path::PathBuf};
+use syn::{
+    parse::{Parse, ParseStream},
+    parse_macro_input, Ident, LitBool, LitStr, Token,
+};
+
+enum Define {
+    Value(String, String),
+    Undef(String),
+}
+
+struct Input {
+    main: LitStr,
+    include_dirs: Vec<LitStr>,
+    defines: Vec<Define>,
+    expand: bool,
+}
+
+impl Parse for Input {
+    fn parse(input: ParseStream) -> syn::Result<Self> {
+        let mut this = Input {
+            main: input.parse()?,
+            include_dirs: Vec::new(),
+            defines: Vec::new(),
+            expand: false,
+        };
+        while !input.is_empty() {
+            input.parse::<Token![,]>()?;
+            if input.is_empty() {
+                break;
+            }
+            let key: Ident = input.parse()?;
+            input.parse::<Token![=]>()?;
+            match key.to_string().as_str() {
+                "include_dir" => this.include_dirs.push(input.parse()?),
+                "define" => {
+                    let value = input.parse::<LitStr>()?.value();
+                    this.defines.push(match value.split_once('=') {
+                        Some((name, value)) => Define::Value(name.into(), value.into()),
+                        // Like `-D NAME` in C compilers.
+                        None => Define::Value(value, String::from("1")),
+                    });
+                }
+                "undef" => this
+                    .defines
+                    .push(Define::Undef(input.parse::<LitStr>()?.value())),
+                "expand_macros" => this.expand = input.parse::<LitBool>()?.value,
+                _ => return Err(syn::Error::new(key.span(), "unknown option")),
+            }
+        }
+        Ok(this)
+    }
+}
+
+fn expand(input: Input) -> Result<proc_macro2::TokenStream, syn::Error> {
+    let root = env::var_os("CARGO_MANIFEST_DIR")
+        .map(PathBuf::from)
+        .ok_or_else(|| syn::Error::new(Span::call_site(), "CARGO_MANIFEST_DIR is not set"))?;
+
+    let mut fs_source = source::Fs::builder();
+    for dir in input.include_dirs.iter() {
+        fs_source = fs_source
+            .include_dir(root.join(dir.value()))
+            .map_err(|e| syn::Error::new(dir.span(), e))?;
+    }
+    let mut builder = Parser::builder()
+        .add_source(fs_source.build())
+        .expand_macros(input.expand);
+    for define in input.defines.iter() {
+        builder = match define {
+            Define::Value(name, value) => builder.add_define(name, value),
+            Define::Undef(name) => builder.add_flag(name.clone(), false),
+        };
+    }
+    let node = builder
+        .build()
+        .parse(&root.join(input.main.value()))
+        .map_err(|e| syn::Error::new(input.main.span(), e))?;
+
+    let (code, mut index) = node.collect();
+    index.map_files(|path| path.strip_prefix(&root).unwrap_or(path).to_path_buf());
+    let index = Literal::byte_string(&index.to_bytes());
+    // Make compiler rebuild the crate when any of the files changes.
+    let files = node
+        .files()
+        .into_iter()
+        .map(|path| path.to_string_lossy().into_owned());
+
+    Ok(quote! {
+        {
+            #(const _: &[u8] = include_bytes!(#files);)*
+            ::ocl_include::StaticCode {
+                code: #code,
+                index: #index,
+            }
+        }
+    })
+}
+
+/// Collects OpenCL source file at compile time and expands to `ocl_include::StaticCode`.
+///
+/// Paths are relative to the crate root (`CARGO_MANIFEST_DIR`).
+/// Options follow the path: `include_dir = "DIR"`, `define = "NAME"` or `define = "NAME=VALUE"`,
+/// `undef = "NAME"` and `expand_macros = true`.
+/// Like `-D NAME` in C compilers, `define = "NAME"` defines the name as `1`.
+///
+/// Parsing errors are reported as compile errors.
+#[proc_macro]
+pub fn include_cl(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as Input);
+    match expand(input) {
+        Ok(tokens) => tokens.into(),
+        Err(err) => err.to_compile_error().into(),
+    }
+}
@@ -0,0 +1,39 @@
+use ocl_include::StaticCode;
+use ocl_include_macros::include_cl;
+
+static KERNEL: StaticCode = include_cl!("../examples/main.c", include_dir = "../examples");
+
+#[test]
+fn include_cl() {
+    let lines = KERNEL.code.lines().collect::<Vec<_>>();
+    assert_eq!(lines[3], "static const int RET_CODE = 0;");
+    assert_eq!(lines[6], "    return RET_CODE;");
+
+    let index = KERNEL.index();
+    let (path, line) = index.search(3).unwrap();
+    assert!(path.ends_with("examples/header.h"));
+    assert_eq!(line, 2);
+    let (path, line) = index.search(6).unwrap();
+    assert!(path.ends_with("examples/main.c"));
+    assert_eq!(line, 3);
+}
+
+static FLAG: StaticCode = include_cl!("tests/kernels/flag.cl", define = "USE_DOUBLE");
+
+#[test]
+fn include_cl_flag() {
+    assert_eq!(FLAG.code.lines().nth(1), Some("double"));
+    assert!(!FLAG.code.contains("float"));
+}
+
+static EXPANDED: StaticCode = include_cl!("tests/kernels/expand.cl", expand_macros = true);
+
+#[test]
+fn include_cl_columns() {
+    let lines = EXPANDED.code.lines().collect::<Vec<_>>();
+    assert_eq!(lines[1], "int a = ((2) * (2)); int b = 1;");
+
+    let (path, line, col) = EXPANDED.index().search_position(1, 21).unwrap();
+    assert_eq!(path, std::path::Path::new("tests/kernels/expand.cl"));
+    assert_eq!((line, col), (1, 16));
+}
@@ -0,0 +1,2 @@
+#define SQR(x) ((x) * (x))
+int a = SQR(2); int b = 1;
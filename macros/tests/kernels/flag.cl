@@ -0,0 +1,5 @@
+#if USE_DOUBLE
+double
+#else
+float
+#endif
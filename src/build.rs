@@ -0,0 +1,217 @@
+//! Helper for build scripts.
+//!
+//! ```no_run
+//! // build.rs
+//! use ocl_include::build::Bundle;
+//!
+//! fn main() {
+//!     Bundle::new("kernels/main.cl")
+//!         .include_dir("kernels/include")
+//!         .add_flag("USE_DOUBLE", true)
+//!         .bundle()
+//!         .unwrap();
+//! }
+//!
+//! // src/lib.rs
+//! // const KERNEL: &str = include_str!(concat!(env!("OUT_DIR"), "/main.cl"));
+//! ```
+
+use crate::{error::Error, parse::Parser, source};
+use std::{
+    env, error, fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Error occurred during bundling.
+#[derive(Debug)]
+pub enum BuildError {
+    /// Failed to parse the kernel.
+    Parse(Error),
+    /// Failed to access the file or directory that is not a source file, e.g. output one.
+    ///
+    /// `path` is `None` if the error is not related to a specific path, e.g. `OUT_DIR` is not set.
+    Io {
+        path: Option<PathBuf>,
+        error: io::Error,
+    },
+}
+
+impl From<Error> for BuildError {
+    fn from(error: Error) -> Self {
+        BuildError::Parse(error)
+    }
+}
+
+impl fmt::Display for BuildError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BuildError::Parse(error) => write!(f, "{}", error),
+            BuildError::Io {
+                path: Some(path),
+                error,
+            } => write!(f, "{:?}: {}", path, error),
+            BuildError::Io { path: None, error } => write!(f, "{}", error),
+        }
+    }
+}
+
+impl error::Error for BuildError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            BuildError::Parse(error) => Some(error),
+            BuildError::Io { error, .. } => Some(error),
+        }
+    }
+}
+
+/// Result of bundling.
+pub struct Bundled {
+    /// Path of the file with collected source code.
+    pub source: PathBuf,
+    /// Path of the index file, if it was requested.
+    pub index: Option<PathBuf>,
+    /// All files the include tree touched.
+    pub files: Vec<PathBuf>,
+}
+
+/// Collects the kernel source and writes it into `OUT_DIR`,
+/// printing `cargo:rerun-if-changed` for each file the kernel depends on.
+///
+/// Relative paths are resolved against the current directory,
+/// which is the package root when running in build script.
+pub struct Bundle {
+    main: PathBuf,
+    include_dirs: Vec<PathBuf>,
+    defines: Vec<(String, Option<String>)>,
+    expand: bool,
+    index: bool,
+    out_dir: Option<PathBuf>,
+    rerun_if_changed: bool,
+}
+
+fn build_error(path: &Path) -> impl FnOnce(io::Error) -> BuildError + '_ {
+    move |error| BuildError::Io {
+        path: Some(path.to_path_buf()),
+        error,
+    }
+}
+
+impl Bundle {
+    pub fn new<P: AsRef<Path>>(main: P) -> Self {
+        Self {
+            main: main.as_ref().to_path_buf(),
+            include_dirs: Vec::new(),
+            defines: Vec::new(),
+            expand: false,
+            index: false,
+            out_dir: None,
+            rerun_if_changed: true,
+        }
+    }
+
+    pub fn include_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.include_dirs.push(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Marks the name as defined with empty value or as not defined, like `ParserBuilder::add_flag`.
+    ///
+    /// Such a name could be checked only by `#ifdef` and `defined`, use `add_define` with `"1"` for `#if NAME`.
+    pub fn add_flag<N: Into<String>>(mut self, name: N, value: bool) -> Self {
+        let value = if value { Some(String::new()) } else { None };
+        self.defines.push((name.into(), value));
+        self
+    }
+
+    /// Defines the name with the value.
+    pub fn add_define<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Self {
+        self.defines.push((name.into(), Some(value.into())));
+        self
+    }
+
+    /// Enables expansion of all known macros, see `ParserBuilder::expand_macros`.
+    pub fn expand_macros(mut self, enable: bool) -> Self {
+        self.expand = enable;
+        self
+    }
+
+    /// Also writes index next to the source with `.idx` extension, see `Index::to_bytes`.
+    pub fn write_index(mut self, enable: bool) -> Self {
+        self.index = enable;
+        self
+    }
+
+    /// Directory to write files into instead of `OUT_DIR`.
+    pub fn out_dir<P: AsRef<Path>>(mut self, dir: P) -> Self {
+        self.out_dir = Some(dir.as_ref().to_path_buf());
+        self
+    }
+
+    /// Whether to print `cargo:rerun-if-changed` for each file (enabled by default).
+    pub fn rerun_if_changed(mut self, enable: bool) -> Self {
+        self.rerun_if_changed = enable;
+        self
+    }
+
+    /// Parses the kernel and writes the result.
+    ///
+    /// Output file has the same name as the main file, output directory is created if it does not exist.
+    pub fn bundle(self) -> Result<Bundled, BuildError> {
+        let cwd = env::current_dir().map_err(|error| BuildError::Io { path: None, error })?;
+
+        let mut fs_source = source::Fs::builder();
+        for dir in self.include_dirs.iter() {
+            let dir = cwd.join(dir);
+            fs_source = fs_source.include_dir(&dir).map_err(build_error(&dir))?;
+        }
+        let mut builder = Parser::builder()
+            .add_source(fs_source.build())
+            .expand_macros(self.expand);
+        for (name, value) in self.defines {
+            builder = match value {
+                Some(value) => builder.add_define(name, value),
+                None => builder.add_flag(name, false),
+            };
+        }
+        let node = builder.build().parse(&cwd.join(&self.main))?;
+
+        let files = node.files();
+        if self.rerun_if_changed {
+            for file in files.iter() {
+                println!("cargo:rerun-if-changed={}", file.display());
+            }
+        }
+
+        let out_dir = match self.out_dir {
+            Some(dir) => dir,
+            None => match env::var_os("OUT_DIR") {
+                Some(dir) => PathBuf::from(dir),
+                None => {
+                    return Err(BuildError::Io {
+                        path: None,
+                        error: io::Error::new(io::ErrorKind::NotFound, "OUT_DIR is not set"),
+                    })
+                }
+            },
+        };
+        fs::create_dir_all(&out_dir).map_err(build_error(&out_dir))?;
+        let name = self.main.file_name().unwrap_or(self.main.as_os_str());
+        let source_path = out_dir.join(name);
+        let (source, index) = node.collect();
+        fs::write(&source_path, &source).map_err(build_error(&source_path))?;
+
+        let index_path = if self.index {
+            let path = source_path.with_extension("idx");
+            fs::write(&path, index.to_bytes()).map_err(build_error(&path))?;
+            Some(path)
+        } else {
+            None
+        };
+
+        Ok(Bundled {
+            source: source_path,
+            index: index_path,
+            files,
+        })
+    }
+}
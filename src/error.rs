@@ -0,0 +1,170 @@
+use crate::source;
+use std::{
+    error, fmt, io,
+    path::{Path, PathBuf},
+};
+
+/// Location of the error in the source code.
+///
+/// Line and column numbers are zero-based.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Location {
+    pub path: PathBuf,
+    pub line: usize,
+    pub column: usize,
+    /// Chain of the files including the erroneous one with line numbers of `#include` directives,
+    /// starting from the closest one and ending with the root file.
+    pub stack: Vec<(PathBuf, usize)>,
+}
+
+impl Location {
+    pub(crate) fn new(path: &Path, line: usize, column: usize) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            line,
+            column,
+            stack: Vec::new(),
+        }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            self.path.display(),
+            self.line + 1,
+            self.column + 1
+        )?;
+        for (path, line) in self.stack.iter() {
+            write!(f, "\nincluded from {}:{}", path.display(), line + 1)?;
+        }
+        Ok(())
+    }
+}
+
+/// Error occurred during parsing.
+///
+/// Location is `None` only when the error is related to the root file.
+#[derive(Debug)]
+pub enum Error {
+    /// File is not found by any of sources.
+    NotFound {
+        path: PathBuf,
+        location: Option<Location>,
+    },
+    /// File includes itself recursively without `#pragma once`.
+    Recursion {
+        path: PathBuf,
+        location: Option<Location>,
+    },
+    /// Sandboxed source refused to read the file outside of its include directories.
+    Forbidden {
+        path: PathBuf,
+        location: Option<Location>,
+    },
+    /// Source failed to read the file.
+    Io {
+        path: PathBuf,
+        error: io::Error,
+        location: Option<Location>,
+    },
+    /// Malformed `#include` directive.
+    BadInclude { location: Location },
+    /// `#elif` without matching `#if` or after `#else`.
+    UnexpectedElif { location: Location },
+    /// `#else` without matching `#if` or after another `#else`.
+    UnexpectedElse { location: Location },
+    /// `#endif` without matching `#if`.
+    UnexpectedEndif { location: Location },
+}
+
+impl Error {
+    pub(crate) fn from_io(path: &Path, error: io::Error) -> Self {
+        let path = path.to_path_buf();
+        match error.kind() {
+            io::ErrorKind::NotFound => Error::NotFound {
+                path,
+                location: None,
+            },
+            io::ErrorKind::PermissionDenied if matches!(error.get_ref(), Some(e) if e.is::<source::fs::OutsideRoots>()) => {
+                Error::Forbidden {
+                    path,
+                    location: None,
+                }
+            }
+            _ => Error::Io {
+                path,
+                error,
+                location: None,
+            },
+        }
+    }
+
+    pub fn location(&self) -> Option<&Location> {
+        match self {
+            Error::NotFound { location, .. }
+            | Error::Recursion { location, .. }
+            | Error::Forbidden { location, .. }
+            | Error::Io { location, .. } => location.as_ref(),
+            Error::BadInclude { location }
+            | Error::UnexpectedElif { location }
+            | Error::UnexpectedElse { location }
+            | Error::UnexpectedEndif { location } => Some(location),
+        }
+    }
+
+    /// Sets the location of the error if it is unknown,
+    /// otherwise adds the include directive to the include stack.
+    pub(crate) fn included_from(mut self, path: &Path, line: usize, column: usize) -> Self {
+        let location = match &mut self {
+            Error::NotFound { location, .. }
+            | Error::Recursion { location, .. }
+            | Error::Forbidden { location, .. }
+            | Error::Io { location, .. } => location,
+            Error::BadInclude { location }
+            | Error::UnexpectedElif { location }
+            | Error::UnexpectedElse { location }
+            | Error::UnexpectedEndif { location } => {
+                location.stack.push((path.to_path_buf(), line));
+                return self;
+            }
+        };
+        match location {
+            Some(location) => location.stack.push((path.to_path_buf(), line)),
+            None => *location = Some(Location::new(path, line, column)),
+        }
+        self
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound { path, .. } => write!(f, "file {:?} not found", path)?,
+            Error::Recursion { path, .. } => write!(f, "recursive inclusion of {:?}", path)?,
+            Error::Forbidden { path, .. } => {
+                write!(f, "{:?} is outside of include directories", path)?
+            }
+            Error::Io { path, error, .. } => write!(f, "cannot read {:?}: {}", path, error)?,
+            Error::BadInclude { .. } => write!(f, "bad #include syntax")?,
+            Error::UnexpectedElif { .. } => write!(f, "unexpected #elif")?,
+            Error::UnexpectedElse { .. } => write!(f, "unexpected #else")?,
+            Error::UnexpectedEndif { .. } => write!(f, "unexpected #endif")?,
+        }
+        match self.location() {
+            Some(location) => write!(f, "\nat {}", location),
+            None => Ok(()),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Error::Io { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use crate::{json::quote, node::Node};
+use std::path::{Path, PathBuf};
+
+/// Format of exported include tree or graph.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GraphFormat {
+    /// Graphviz DOT.
+    Dot,
+    /// JSON, the structure is described at `Node::include_tree` and `Node::include_graph`.
+    Json,
+}
+
+fn dot_label(path: &Path, lines: Option<usize>) -> String {
+    let name = path
+        .to_string_lossy()
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"");
+    match lines {
+        Some(lines) => format!("\"{}\\n{} lines\"", name, lines),
+        None => format!("\"{}\"", name),
+    }
+}
+
+/// Include of the file in the deduplicated graph.
+#[derive(PartialEq, Eq)]
+struct Edge {
+    from: usize,
+    to: usize,
+    line: usize,
+    skipped: bool,
+}
+
+#[derive(Default)]
+struct Graph {
+    files: Vec<(PathBuf, usize)>,
+    edges: Vec<Edge>,
+}
+
+impl Graph {
+    fn file(&mut self, path: &Path, lines: usize) -> usize {
+        match self.files.iter().position(|(p, _)| p == path) {
+            Some(id) => id,
+            None => {
+                self.files.push((path.to_path_buf(), lines));
+                self.files.len() - 1
+            }
+        }
+    }
+
+    fn edge(&mut self, edge: Edge) {
+        if !self.edges.contains(&edge) {
+            self.edges.push(edge);
+        }
+    }
+
+    fn build(&mut self, node: &Node) {
+        let from = self.file(node.name(), node.lines_count());
+        for (child, line) in node.children() {
+            let to = self.file(child.name(), child.lines_count());
+            self.edge(Edge {
+                from,
+                to,
+                line,
+                skipped: false,
+            });
+            self.build(child);
+        }
+        for (path, line) in node.skipped() {
+            let to = self.file(path, 0);
+            self.edge(Edge {
+                from,
+                to,
+                line,
+                skipped: true,
+            });
+        }
+    }
+}
+
+impl Node {
+    fn tree_dot(&self, out: &mut String, count: &mut usize) -> usize {
+        let id = *count;
+        *count += 1;
+        out.push_str(&format!(
+            "    n{} [label={}];\n",
+            id,
+            dot_label(self.name(), Some(self.lines_count()))
+        ));
+        for (child, line) in self.children() {
+            let child_id = child.tree_dot(out, count);
+            out.push_str(&format!(
+                "    n{} -> n{} [label=\"{}\"];\n",
+                id,
+                child_id,
+                line + 1
+            ));
+        }
+        for (path, line) in self.skipped() {
+            out.push_str(&format!(
+                "    n{} [label={}, style=dashed];\n",
+                *count,
+                dot_label(path, None)
+            ));
+            out.push_str(&format!(
+                "    n{} -> n{} [label=\"{}\", style=dashed];\n",
+                id,
+                *count,
+                line + 1
+            ));
+            *count += 1;
+        }
+        id
+    }
+
+    fn tree_json(&self) -> String {
+        let includes = self
+            .children()
+            .map(|(child, line)| {
+                format!("{{\"line\":{},\"node\":{}}}", line + 1, child.tree_json())
+            })
+            .collect::<Vec<_>>();
+        let skipped = self
+            .skipped()
+            .map(|(path, line)| {
+                format!(
+                    "{{\"line\":{},\"path\":{}}}",
+                    line + 1,
+                    quote(&path.to_string_lossy())
+                )
+            })
+            .collect::<Vec<_>>();
+        format!(
+            "{{\"path\":{},\"lines\":{},\"includes\":[{}],\"skipped\":[{}]}}",
+            quote(&self.name().to_string_lossy()),
+            self.lines_count(),
+            includes.join(","),
+            skipped.join(",")
+        )
+    }
+
+    /// Exports the include tree where each inclusion of the file is a separate vertex.
+    ///
+    /// Includes skipped because of `#pragma once` are shown as dashed leaves in DOT.
+    /// JSON is a nested object `{"path", "lines", "includes": [{"line", "node"}], "skipped": [{"line", "path"}]}`.
+    /// Line numbers of includes are one-based.
+    pub fn include_tree(&self, format: GraphFormat) -> String {
+        match format {
+            GraphFormat::Dot => {
+                let mut out = String::from("digraph includes {\n");
+                self.tree_dot(&mut out, &mut 0);
+                out.push_str("}\n");
+                out
+            }
+            GraphFormat::Json => self.tree_json(),
+        }
+    }
+
+    /// Exports the include graph with a single vertex for each file,
+    /// including the edges cut by `#pragma once` (dashed in DOT).
+    ///
+    /// JSON is `{"files": [{"path", "lines"}], "edges": [{"from", "to", "line", "skipped"}]}`
+    /// where `from` and `to` are positions in `files`.
+    /// Line numbers of includes are one-based.
+    pub fn include_graph(&self, format: GraphFormat) -> String {
+        let mut graph = Graph::default();
+        graph.build(self);
+        match format {
+            GraphFormat::Dot => {
+                let mut out = String::from("digraph includes {\n");
+                for (id, (path, lines)) in graph.files.iter().enumerate() {
+                    out.push_str(&format!(
+                        "    f{} [label={}];\n",
+                        id,
+                        dot_label(path, Some(*lines))
+                    ));
+                }
+                for edge in graph.edges.iter() {
+                    out.push_str(&format!(
+                        "    f{} -> f{} [label=\"{}\"{}];\n",
+                        edge.from,
+                        edge.to,
+                        edge.line + 1,
+                        if edge.skipped { ", style=dashed" } else { "" }
+                    ));
+                }
+                out.push_str("}\n");
+                out
+            }
+            GraphFormat::Json => {
+                let files = graph
+                    .files
+                    .iter()
+                    .map(|(path, lines)| {
+                        format!(
+                            "{{\"path\":{},\"lines\":{}}}",
+                            quote(&path.to_string_lossy()),
+                            lines
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                let edges = graph
+                    .edges
+                    .iter()
+                    .map(|e| {
+                        format!(
+                            "{{\"from\":{},\"to\":{},\"line\":{},\"skipped\":{}}}",
+                            e.from,
+                            e.to,
+                            e.line + 1,
+                            e.skipped
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                format!(
+                    "{{\"files\":[{}],\"edges\":[{}]}}",
+                    files.join(","),
+                    edges.join(",")
+                )
+            }
+        }
+    }
+}
@@ -0,0 +1,460 @@
+use std::{
+    cmp::Ordering,
+    collections::{BTreeMap, BTreeSet, HashMap},
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+mod source_map;
+
+/// Header of binary index, the last byte is the format version.
+const MAGIC: &[u8; 5] = b"OCLI\x01";
+
+/// Mapping of columns of the generated line to the original ones,
+/// it is stored only for lines which content was changed by the parser.
+///
+/// Columns are byte offsets.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub(crate) struct ColumnMap {
+    /// Chunks of generated line as `(generated_start, original_start, copied)`.
+    /// Columns of copied chunk are mapped one-to-one, the whole replaced chunk is mapped to its start.
+    pub chunks: Vec<(usize, usize, bool)>,
+    /// Offsets where original lines start if the line was joined from several ones.
+    pub lines: Vec<usize>,
+}
+
+impl ColumnMap {
+    /// Maps column to the number of the original line relative to the first one and the column in it.
+    fn map(&self, col: usize) -> (usize, usize) {
+        let offset = match self.chunks.iter().rposition(|(gen, _, _)| *gen <= col) {
+            Some(i) => match self.chunks[i] {
+                (gen, orig, true) => orig.saturating_add(col - gen),
+                (_, orig, false) => orig,
+            },
+            None => col,
+        };
+        self.split_offset(offset)
+    }
+
+    /// Splits offset in joined original lines into line number and column.
+    fn split_offset(&self, offset: usize) -> (usize, usize) {
+        match self.lines.iter().rposition(|start| *start <= offset) {
+            Some(i) => (i, offset - self.lines[i]),
+            None => (0, offset),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct IndexEntry {
+    name: Arc<PathBuf>,
+    start: usize,
+    range: Range<usize>,
+}
+
+/// Index that maps generated code locations to their origins
+pub struct Index {
+    segs: Vec<IndexEntry>,
+    size: usize,
+    columns: BTreeMap<usize, ColumnMap>,
+    /// Lines of inserted `#line` directives.
+    directives: BTreeSet<usize>,
+    /// Positions of segments of each file, used for reverse lookup.
+    files_segs: HashMap<PathBuf, Vec<usize>>,
+}
+
+impl Index {
+    pub(crate) fn new() -> Self {
+        Self {
+            segs: Vec::new(),
+            size: 0,
+            columns: BTreeMap::new(),
+            directives: BTreeSet::new(),
+            files_segs: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn push(&mut self, name: Arc<PathBuf>, start: usize, size: usize) {
+        match self.files_segs.get_mut(name.as_path()) {
+            Some(segs) => segs.push(self.segs.len()),
+            None => {
+                self.files_segs
+                    .insert((*name).clone(), vec![self.segs.len()]);
+            }
+        }
+        self.segs.push(IndexEntry {
+            name,
+            start,
+            range: self.size..(self.size + size),
+        });
+        self.size += size;
+    }
+
+    /// Number of lines covered by the index.
+    pub(crate) fn len(&self) -> usize {
+        self.size
+    }
+
+    /// Pushes the line of `#line` directive that is mapped to the `start` line of the file.
+    pub(crate) fn push_directive(&mut self, name: Arc<PathBuf>, start: usize) {
+        self.directives.insert(self.size);
+        self.push(name, start, 1);
+    }
+
+    pub(crate) fn push_columns(&mut self, pos: usize, map: ColumnMap) {
+        self.columns.insert(pos, map);
+    }
+
+    /// Creates index from segments, each is a file name, the line in this file
+    /// where the segment starts and the number of lines in the segment.
+    ///
+    /// Segments carry no column mappings, so `search_position` returns columns as they are.
+    /// Use `to_bytes` and `from_bytes` to store the index completely.
+    pub fn from_segments<P: AsRef<Path>>(segs: &[(P, usize, usize)]) -> Self {
+        let mut index = Self::new();
+        let mut names: HashMap<&Path, Arc<PathBuf>> = HashMap::new();
+        for (name, start, size) in segs.iter() {
+            let name = names
+                .entry(name.as_ref())
+                .or_insert_with(|| Arc::new(name.as_ref().to_path_buf()))
+                .clone();
+            index.push(name, *start, *size);
+        }
+        index
+    }
+
+    /// Segments of the index in the form accepted by `from_segments`.
+    pub fn segments(&self) -> Vec<(PathBuf, usize, usize)> {
+        self.segs
+            .iter()
+            .map(|seg| ((*seg.name).clone(), seg.start, seg.range.len()))
+            .collect()
+    }
+
+    /// Renames source files, e.g. to make their paths relative, keeping the rest of the index.
+    pub fn map_files<F: FnMut(&Path) -> PathBuf>(&mut self, mut f: F) {
+        let mut names: HashMap<PathBuf, PathBuf> = HashMap::new();
+        let segs = self
+            .segments()
+            .into_iter()
+            .map(|(name, start, size)| {
+                let name = names.entry(name).or_insert_with_key(|name| f(name)).clone();
+                (name, start, size)
+            })
+            .collect::<Vec<_>>();
+        let columns = std::mem::take(&mut self.columns);
+        let directives = std::mem::take(&mut self.directives);
+        *self = Self::from_segments(&segs);
+        self.columns = columns;
+        self.directives = directives;
+    }
+
+    /// List of source files in order of their first appearance in generated code.
+    ///
+    /// Position of file in this list is its identifier in `#line` directives when `LineNames::Ids` is used.
+    pub fn files(&self) -> Vec<PathBuf> {
+        let mut files: Vec<PathBuf> = Vec::new();
+        for seg in self.segs.iter() {
+            if !files.iter().any(|f| *f == *seg.name) {
+                files.push((*seg.name).clone());
+            }
+        }
+        files
+    }
+
+    /// Maps line number in generated code to source file name and position in it
+    pub fn search(&self, pos: usize) -> Option<(PathBuf, usize)> {
+        match self.segs.binary_search_by(|seg| {
+            if pos < seg.range.start {
+                Ordering::Greater
+            } else if pos >= seg.range.end {
+                Ordering::Less
+            } else {
+                Ordering::Equal
+            }
+        }) {
+            Ok(i) => {
+                let seg = &self.segs[i];
+                Some(((*seg.name).clone(), pos - seg.range.start + seg.start))
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Finds all places in generated code where the line of the source file appears.
+    ///
+    /// Line may appear several times if the file is included more than once, adjacent lines are merged into a single range.
+    /// Lines of `#line` directives are not included, though `search` maps them to the following lines.
+    /// Lines joined into a single generated one, e.g. by macro invocation spanning several lines, are found in it.
+    pub fn search_origin(&self, path: &Path, pos: usize) -> Vec<Range<usize>> {
+        let mut ranges: Vec<Range<usize>> = Vec::new();
+        let segs = match self.files_segs.get(path) {
+            Some(segs) => segs,
+            None => return ranges,
+        };
+        for seg in segs.iter().map(|i| &self.segs[*i]) {
+            if pos < seg.start || pos - seg.start >= seg.range.len() {
+                continue;
+            }
+            let mut line = seg.range.start + (pos - seg.start);
+            if self.directives.contains(&line) {
+                continue;
+            }
+            // Line joined into the previous generated one, e.g. by multiline macro invocation.
+            if let Some((&joined, map)) = self.columns.range(seg.range.start..line).next_back() {
+                if joined + map.lines.len() > line {
+                    line = joined;
+                }
+            }
+            match ranges.last_mut() {
+                Some(range) if range.end == line => range.end += 1,
+                _ => ranges.push(line..(line + 1)),
+            }
+        }
+        ranges
+    }
+
+    /// Maps position in generated code to source file name, line and column in it.
+    ///
+    /// Columns are byte offsets. They differ from the original ones
+    /// only in lines changed by the parser, e.g. by macro expansion or rewritten directives.
+    /// Column inside of an expanded macro is mapped to the start of its invocation.
+    pub fn search_position(&self, line: usize, col: usize) -> Option<(PathBuf, usize, usize)> {
+        let (name, pos) = self.search(line)?;
+        Some(match self.columns.get(&line) {
+            Some(map) => {
+                let (offset, col) = map.map(col);
+                (name, pos.saturating_add(offset), col)
+            }
+            None => (name, pos, col),
+        })
+    }
+
+    /// Files and segments referring to them by position in the list of files.
+    fn table(&self) -> (Vec<PathBuf>, Vec<(usize, usize, usize)>) {
+        let files = self.files();
+        let ids = files
+            .iter()
+            .enumerate()
+            .map(|(i, f)| (f.as_path(), i))
+            .collect::<HashMap<_, _>>();
+        let segs = self
+            .segs
+            .iter()
+            .map(|seg| (ids[seg.name.as_path()], seg.start, seg.range.len()))
+            .collect();
+        (files, segs)
+    }
+
+    /// Restores index from possibly untrusted data, checking that it is consistent.
+    fn from_table(
+        files: Vec<PathBuf>,
+        segs: &[(usize, usize, usize)],
+        columns: Vec<(usize, ColumnMap)>,
+        directives: Vec<usize>,
+    ) -> Result<Self, String> {
+        let files = files.into_iter().map(Arc::new).collect::<Vec<_>>();
+        let mut index = Self::new();
+        for &(id, start, size) in segs.iter() {
+            let name = files
+                .get(id)
+                .ok_or_else(|| format!("file id {} is out of range", id))?;
+            if index.size.checked_add(size).is_none() || start.checked_add(size).is_none() {
+                return Err(format!("segment size {} is too large", size));
+            }
+            index.push(name.clone(), start, size);
+        }
+        for (pos, map) in columns {
+            if pos >= index.size {
+                return Err(format!("column mapping line {} is out of range", pos));
+            }
+            index.columns.insert(pos, map);
+        }
+        for pos in directives {
+            if pos >= index.size {
+                return Err(format!("directive line {} is out of range", pos));
+            }
+            index.directives.insert(pos);
+        }
+        Ok(index)
+    }
+
+    /// Serializes index into compact binary form.
+    ///
+    /// The format is stable: it starts with `OCLI` and version byte,
+    /// followed by the list of files, the list of segments, column mappings of changed lines
+    /// and lines of `#line` directives, all numbers are LEB128-encoded.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let (files, segs) = self.table();
+        let mut bytes = MAGIC.to_vec();
+        write_uint(&mut bytes, files.len());
+        for file in files.iter() {
+            let name = file.to_string_lossy();
+            write_uint(&mut bytes, name.len());
+            bytes.extend_from_slice(name.as_bytes());
+        }
+        write_uint(&mut bytes, segs.len());
+        for (id, start, size) in segs {
+            write_uint(&mut bytes, id);
+            write_uint(&mut bytes, start);
+            write_uint(&mut bytes, size);
+        }
+        write_uint(&mut bytes, self.columns.len());
+        for (pos, map) in self.columns.iter() {
+            write_uint(&mut bytes, *pos);
+            write_uint(&mut bytes, map.chunks.len());
+            for (gen, orig, copied) in map.chunks.iter() {
+                write_uint(&mut bytes, *gen);
+                write_uint(&mut bytes, *orig);
+                write_uint(&mut bytes, *copied as usize);
+            }
+            write_uint(&mut bytes, map.lines.len());
+            for start in map.lines.iter() {
+                write_uint(&mut bytes, *start);
+            }
+        }
+        write_uint(&mut bytes, self.directives.len());
+        for pos in self.directives.iter() {
+            write_uint(&mut bytes, *pos);
+        }
+        bytes
+    }
+
+    /// Deserializes index produced by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+        let mut reader = Reader { bytes, pos: 0 };
+        let header = reader.take(MAGIC.len())?;
+        if header[..4] != MAGIC[..4] {
+            return Err(invalid(String::from("not an index")));
+        }
+        if header[4] != MAGIC[4] {
+            return Err(invalid(format!(
+                "unsupported index version {}, expected {}",
+                header[4], MAGIC[4]
+            )));
+        }
+        let mut files = Vec::new();
+        for _ in 0..reader.uint()? {
+            let len = reader.uint()?;
+            let name =
+                std::str::from_utf8(reader.take(len)?).map_err(|e| invalid(e.to_string()))?;
+            files.push(PathBuf::from(name));
+        }
+        let mut segs = Vec::new();
+        for _ in 0..reader.uint()? {
+            segs.push((reader.uint()?, reader.uint()?, reader.uint()?));
+        }
+        let mut columns = Vec::new();
+        for _ in 0..reader.uint()? {
+            let pos = reader.uint()?;
+            let mut map = ColumnMap::default();
+            for _ in 0..reader.uint()? {
+                map.chunks
+                    .push((reader.uint()?, reader.uint()?, reader.uint()? != 0));
+            }
+            for _ in 0..reader.uint()? {
+                map.lines.push(reader.uint()?);
+            }
+            columns.push((pos, map));
+        }
+        let mut directives = Vec::new();
+        for _ in 0..reader.uint()? {
+            directives.push(reader.uint()?);
+        }
+        if reader.pos != bytes.len() {
+            return Err(invalid(String::from("trailing bytes after index")));
+        }
+        Self::from_table(files, &segs, columns, directives).map_err(invalid)
+    }
+}
+
+fn write_uint(bytes: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        bytes.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    bytes.push(value as u8);
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> io::Result<&'a [u8]> {
+        match self.bytes.get(self.pos..).and_then(|rest| rest.get(..len)) {
+            Some(slice) => {
+                self.pos += len;
+                Ok(slice)
+            }
+            None => Err(io::ErrorKind::UnexpectedEof.into()),
+        }
+    }
+
+    fn uint(&mut self) -> io::Result<usize> {
+        let mut value = 0usize;
+        for shift in (0..usize::BITS).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= ((byte & 0x7f) as usize) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "integer is too large",
+        ))
+    }
+}
+
+/// Index is serialized as `{"files": [...], "segments": [[file_id, start, size], ...], "columns": [...], "directives": [...]}`,
+/// where `columns` is omitted if no line was changed and `directives` is omitted if there are no `#line` directives.
+#[cfg(feature = "serde")]
+mod serde_impl {
+    use super::{ColumnMap, Index};
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+    use std::path::PathBuf;
+
+    #[derive(Serialize, Deserialize)]
+    struct Table {
+        files: Vec<PathBuf>,
+        segments: Vec<(usize, usize, usize)>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        columns: Vec<(usize, ColumnMap)>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        directives: Vec<usize>,
+    }
+
+    impl Serialize for Index {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let (files, segments) = self.table();
+            let columns = self.columns.clone().into_iter().collect();
+            let directives = self.directives.iter().cloned().collect();
+            Table {
+                files,
+                segments,
+                columns,
+                directives,
+            }
+            .serialize(serializer)
+        }
+    }
+
+    impl<'de> Deserialize<'de> for Index {
+        fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+            let table = Table::deserialize(deserializer)?;
+            Index::from_table(
+                table.files,
+                &table.segments,
+                table.columns,
+                table.directives,
+            )
+            .map_err(D::Error::custom)
+        }
+    }
+}
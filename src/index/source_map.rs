@@ -0,0 +1,123 @@
+use super::Index;
+use crate::{json::quote, source::Source};
+use std::path::PathBuf;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// Appends Base64 VLQ representation of the number.
+fn write_vlq(out: &mut String, value: isize) {
+    let mut value = if value < 0 {
+        ((-value as usize) << 1) | 1
+    } else {
+        (value as usize) << 1
+    };
+    loop {
+        let mut digit = value & 0x1f;
+        value >>= 5;
+        if value > 0 {
+            digit |= 0x20;
+        }
+        out.push(BASE64[digit] as char);
+        if value == 0 {
+            break;
+        }
+    }
+}
+
+/// Mapping fields are relative to the previous mapping.
+#[derive(Default)]
+struct Mappings {
+    text: String,
+    col: usize,
+    source: usize,
+    line: usize,
+    orig_col: usize,
+}
+
+impl Mappings {
+    fn next_line(&mut self) {
+        self.text.push(';');
+        self.col = 0;
+    }
+
+    fn push(&mut self, col: usize, source: usize, line: usize, orig_col: usize) {
+        if !self.text.is_empty() && !self.text.ends_with(';') {
+            self.text.push(',');
+        }
+        write_vlq(&mut self.text, col as isize - self.col as isize);
+        write_vlq(&mut self.text, source as isize - self.source as isize);
+        write_vlq(&mut self.text, line as isize - self.line as isize);
+        write_vlq(&mut self.text, orig_col as isize - self.orig_col as isize);
+        self.col = col;
+        self.source = source;
+        self.line = line;
+        self.orig_col = orig_col;
+    }
+}
+
+impl Index {
+    fn mappings(&self, files: &[PathBuf]) -> String {
+        let mut mappings = Mappings::default();
+        for seg in self.segs.iter() {
+            let source = files.iter().position(|f| *f == *seg.name).unwrap();
+            for (i, pos) in seg.range.clone().enumerate() {
+                if pos > 0 {
+                    mappings.next_line();
+                }
+                let line = seg.start + i;
+                match self.columns.get(&pos) {
+                    Some(map) => {
+                        for (col, orig, _) in map.chunks.iter() {
+                            let (offset, orig_col) = map.split_offset(*orig);
+                            mappings.push(*col, source, line + offset, orig_col);
+                        }
+                    }
+                    None => mappings.push(0, source, line, 0),
+                }
+            }
+        }
+        mappings.text
+    }
+
+    fn source_map_inner(&self, file: &str, source: Option<&dyn Source>) -> String {
+        let files = self.files();
+        let names = files
+            .iter()
+            .map(|f| quote(&f.to_string_lossy()))
+            .collect::<Vec<_>>();
+        let mut out = format!(
+            "{{\"version\":3,\"file\":{},\"sources\":[{}]",
+            quote(file),
+            names.join(",")
+        );
+        if let Some(source) = source {
+            let contents = files
+                .iter()
+                .map(|f| match source.read(f, None) {
+                    Ok((_, text)) => quote(&text),
+                    Err(_) => String::from("null"),
+                })
+                .collect::<Vec<_>>();
+            out.push_str(&format!(",\"sourcesContent\":[{}]", contents.join(",")));
+        }
+        out.push_str(&format!(
+            ",\"names\":[],\"mappings\":{}}}",
+            quote(&self.mappings(&files))
+        ));
+        out
+    }
+
+    /// Generates Source Map v3 document for the generated code named `file`.
+    ///
+    /// Each line is mapped to the beginning of its origin, changed lines are mapped by chunks.
+    pub fn source_map(&self, file: &str) -> String {
+        self.source_map_inner(file, None)
+    }
+
+    /// Generates Source Map v3 document with `sourcesContent` read from the `source`.
+    ///
+    /// Content of the file that cannot be read is `null`.
+    pub fn source_map_with_contents(&self, file: &str, source: &dyn Source) -> String {
+        self.source_map_inner(file, Some(source))
+    }
+}
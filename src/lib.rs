@@ -1,10 +1,21 @@
+mod error;
+mod graph;
+mod index;
+mod json;
+mod log;
 mod node;
 mod parse;
 #[cfg(test)]
 mod tests;
 
+pub mod build;
 pub mod source;
+pub mod watch;
 
+pub use error::*;
+pub use graph::*;
+pub use index::*;
+pub use log::*;
 pub use node::*;
 pub use parse::*;
 
@@ -0,0 +1,123 @@
+use crate::index::Index;
+use lazy_static::lazy_static;
+use regex::Regex;
+
+/// Format of source locations in OpenCL compiler build log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Clang-based compilers: `<kernel>:LINE:COLUMN`, also `<source>`, `<stdin>` and `<input>` names.
+    Clang,
+    /// NVIDIA: `<kernel>:LINE:COLUMN` or `:LINE:COLUMN` with empty name at the beginning of line.
+    Nvidia,
+    /// AMD: legacy `"/tmp/OCL1234T5.cl", line LINE` and ROCm `.../CompileSource:LINE:COLUMN`.
+    Amd,
+    /// Intel: `1:LINE:COLUMN` at the beginning of line.
+    Intel,
+    /// PoCL: `.../program.cl:LINE:COLUMN` or any other `.cl` file it compiles.
+    Pocl,
+}
+
+impl LogFormat {
+    /// All supported formats.
+    pub const ALL: [LogFormat; 5] = [
+        LogFormat::Clang,
+        LogFormat::Nvidia,
+        LogFormat::Amd,
+        LogFormat::Intel,
+        LogFormat::Pocl,
+    ];
+
+    fn patterns(&self) -> &'static [Regex] {
+        match self {
+            LogFormat::Clang => &CLANG,
+            LogFormat::Nvidia => &NVIDIA,
+            LogFormat::Amd => &AMD,
+            LogFormat::Intel => &INTEL,
+            LogFormat::Pocl => &POCL,
+        }
+    }
+}
+
+fn make_regex(expr: &str) -> Regex {
+    Regex::new(expr).unwrap()
+}
+
+lazy_static! {
+    static ref CLANG: Vec<Regex> = vec![make_regex(
+        r#"<(?:kernel|source|stdin|input)>:(?P<line>\d+):(?P<col>\d+)"#
+    )];
+    static ref NVIDIA: Vec<Regex> = vec![
+        make_regex(r#"<kernel>:(?P<line>\d+):(?P<col>\d+)"#),
+        make_regex(r#"^:(?P<line>\d+):(?P<col>\d+)"#),
+    ];
+    static ref AMD: Vec<Regex> = vec![
+        make_regex(r#""[^"]*\.cl", line (?P<line>\d+)"#),
+        make_regex(r#"[^\s:]*CompileSource:(?P<line>\d+):(?P<col>\d+)"#),
+    ];
+    static ref INTEL: Vec<Regex> = vec![make_regex(r#"^\d+:(?P<line>\d+):(?P<col>\d+):"#)];
+    static ref POCL: Vec<Regex> = vec![make_regex(r#"[^\s:"]*\.cl:(?P<line>\d+):(?P<col>\d+)"#)];
+}
+
+/// Replaces locations in generated code found in build log of the specified formats
+/// with locations in original source files as `path:line:column` (or `path:line` if column is unknown).
+///
+/// Locations that cannot be found in index are left untouched.
+pub fn rewrite_log_with(log: &str, index: &Index, formats: &[LogFormat]) -> String {
+    let mut output = String::new();
+    for (i, line) in log.split('\n').enumerate() {
+        if i > 0 {
+            output.push('\n');
+        }
+
+        // Find replacements of all formats, the earlier format wins on overlap.
+        let mut repls = Vec::new();
+        for regex in formats.iter().flat_map(|f| f.patterns().iter()) {
+            for cap in regex.captures_iter(line) {
+                let line_match = cap.name("line").unwrap();
+                let col_match = cap.name("col");
+                let start = cap.get(0).unwrap().start();
+                let end = col_match.unwrap_or(line_match).end();
+                if repls.iter().any(|(s, e, _)| start < *e && *s < end) {
+                    continue;
+                }
+                let pos = match line_match.as_str().parse::<usize>() {
+                    Ok(pos) if pos > 0 => pos - 1,
+                    _ => continue,
+                };
+                let col = col_match.and_then(|m| match m.as_str().parse::<usize>() {
+                    Ok(col) if col > 0 => Some(col - 1),
+                    _ => None,
+                });
+                let repl = match col {
+                    Some(col) => index.search_position(pos, col).map(|(path, pos, col)| {
+                        (end, format!("{}:{}:{}", path.display(), pos + 1, col + 1))
+                    }),
+                    // Original column text is kept after the replaced line.
+                    None => index.search(pos).map(|(path, pos)| {
+                        (line_match.end(), format!("{}:{}", path.display(), pos + 1))
+                    }),
+                };
+                if let Some((end, repl)) = repl {
+                    repls.push((start, end, repl));
+                }
+            }
+        }
+        repls.sort_by_key(|(start, _, _)| *start);
+
+        let mut pos = 0;
+        for (start, end, repl) in repls {
+            output.push_str(&line[pos..start]);
+            output.push_str(&repl);
+            pos = end;
+        }
+        output.push_str(&line[pos..]);
+    }
+    output
+}
+
+/// Replaces locations in generated code found in build log with locations in original source files.
+///
+/// All supported formats are recognized, see `LogFormat`.
+pub fn rewrite_log(log: &str, index: &Index) -> String {
+    rewrite_log_with(log, index, &LogFormat::ALL)
+}
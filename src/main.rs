@@ -0,0 +1,187 @@
+use ocl_include::*;
+use std::{
+    env, fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+};
+
+const USAGE: &str = "\
+Usage: ocl-include [OPTIONS] <INPUT>
+
+Resolves #include directives in OpenCL source file and prints the resulting code.
+Use - as <INPUT> to read the source from stdin.
+
+Options:
+  -I <DIR>            Add directory to include search path
+  -D <NAME>[=<VALUE>] Define name with value (1 by default)
+  -U <NAME>           Mark name as not defined
+  -o <FILE>           Write output to file instead of stdout
+  --index <FILE>      Write binary index that maps output lines to original ones
+  --source-map <FILE> Write Source Map v3 with contents of source files
+  -MF <FILE>          Write Makefile-style dependency file
+  -MT <TARGET>        Target name in dependency file (output file by default)
+  --line-directives   Insert #line directives into output
+  --expand            Expand macros in output
+  -h, --help          Print this message
+";
+
+enum Define {
+    Value(String),
+    Undef,
+}
+
+#[derive(Default)]
+struct Args {
+    input: Option<PathBuf>,
+    include_dirs: Vec<PathBuf>,
+    defines: Vec<(String, Define)>,
+    output: Option<PathBuf>,
+    index: Option<PathBuf>,
+    source_map: Option<PathBuf>,
+    depfile: Option<PathBuf>,
+    target: Option<PathBuf>,
+    line_directives: bool,
+    expand: bool,
+}
+
+fn parse_args<I: Iterator<Item = String>>(mut iter: I) -> Result<Args, String> {
+    let mut args = Args::default();
+    while let Some(arg) = iter.next() {
+        let mut value = |name: &str, prefix: &str| -> Result<String, String> {
+            match arg.strip_prefix(prefix) {
+                Some(v) if !v.is_empty() => Ok(String::from(v)),
+                _ => iter
+                    .next()
+                    .ok_or_else(|| format!("missing value for {}", name)),
+            }
+        };
+        if arg == "-h" || arg == "--help" {
+            print!("{}", USAGE);
+            process::exit(0);
+        } else if arg == "--line-directives" {
+            args.line_directives = true;
+        } else if arg == "--expand" {
+            args.expand = true;
+        } else if arg == "--index" {
+            args.index = Some(value("--index", "--index")?.into());
+        } else if arg == "--source-map" {
+            args.source_map = Some(value("--source-map", "--source-map")?.into());
+        } else if arg.starts_with("-MF") {
+            args.depfile = Some(value("-MF", "-MF")?.into());
+        } else if arg.starts_with("-MT") {
+            args.target = Some(value("-MT", "-MT")?.into());
+        } else if arg.starts_with("-I") {
+            args.include_dirs.push(value("-I", "-I")?.into());
+        } else if arg.starts_with("-D") {
+            let def = value("-D", "-D")?;
+            args.defines.push(match def.find('=') {
+                Some(pos) => (
+                    String::from(&def[..pos]),
+                    Define::Value(String::from(&def[(pos + 1)..])),
+                ),
+                None => (def, Define::Value(String::from("1"))),
+            });
+        } else if arg.starts_with("-U") {
+            args.defines.push((value("-U", "-U")?, Define::Undef));
+        } else if arg.starts_with("-o") {
+            args.output = Some(value("-o", "-o")?.into());
+        } else if arg.starts_with('-') && arg != "-" {
+            return Err(format!("unknown option {}", arg));
+        } else if args.input.replace(arg.into()).is_some() {
+            return Err(String::from("multiple input files"));
+        }
+    }
+    Ok(args)
+}
+
+fn run(args: Args) -> Result<(), String> {
+    let input = args.input.ok_or_else(|| String::from("no input file"))?;
+    let mut sources: Vec<Box<dyn Source>> = Vec::new();
+    let input = if input == Path::new("-") {
+        let mut text = String::new();
+        io::stdin()
+            .read_to_string(&mut text)
+            .map_err(|e| format!("cannot read input: {}", e))?;
+        // Quoted includes are searched relative to the current directory.
+        let input = PathBuf::from("<stdin>");
+        let mem_source = source::Mem::builder()
+            .add_file(&input, text)
+            .map_err(|e| e.to_string())?;
+        sources.push(Box::new(mem_source.build()));
+        input
+    } else {
+        // Relative root path would be searched only in include directories.
+        env::current_dir()
+            .map(|dir| dir.join(&input))
+            .map_err(|e| e.to_string())?
+    };
+
+    let mut fs_source = source::Fs::builder();
+    for dir in args.include_dirs.iter() {
+        fs_source = fs_source
+            .include_dir(dir)
+            .map_err(|e| format!("{:?}: {}", dir, e))?;
+    }
+    sources.push(Box::new(fs_source.build()));
+    let sources = Arc::new(sources);
+    let mut builder = Parser::builder()
+        .add_source(sources.clone())
+        .expand_macros(args.expand);
+    for (name, value) in args.defines {
+        builder = match value {
+            Define::Value(value) => builder.add_define(name, value),
+            Define::Undef => builder.add_flag(name, false),
+        };
+    }
+    let parser = builder.build();
+
+    let node = parser.parse(&input).map_err(|e| e.to_string())?;
+    let (source, index) = if args.line_directives {
+        node.collect_with_lines(&LineNames::Paths)
+    } else {
+        node.collect()
+    };
+
+    match &args.output {
+        Some(path) => fs::write(path, &source),
+        None => io::stdout().write_all(source.as_bytes()),
+    }
+    .map_err(|e| format!("cannot write output: {}", e))?;
+
+    if let Some(path) = &args.index {
+        fs::write(path, index.to_bytes()).map_err(|e| format!("cannot write index: {}", e))?;
+    }
+
+    if let Some(path) = &args.source_map {
+        let file = match &args.output {
+            Some(output) => output.to_string_lossy().into_owned(),
+            None => String::new(),
+        };
+        fs::write(
+            path,
+            index.source_map_with_contents(&file, sources.as_ref()),
+        )
+        .map_err(|e| format!("cannot write source map: {}", e))?;
+    }
+
+    if let Some(path) = &args.depfile {
+        let target = match (&args.target, &args.output) {
+            (Some(target), _) => target.clone(),
+            (None, Some(output)) => output.clone(),
+            (None, None) => input.clone(),
+        };
+        fs::write(path, node.depfile(&target))
+            .map_err(|e| format!("cannot write dependency file: {}", e))?;
+    }
+
+    Ok(())
+}
+
+fn main() {
+    if let Err(e) = parse_args(env::args().skip(1)).and_then(run) {
+        eprintln!("ocl-include: {}", e);
+        process::exit(1);
+    }
+}
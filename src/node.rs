@@ -1,76 +1,86 @@
+use crate::index::{ColumnMap, Index};
 use std::{
-    cmp::Ordering,
+    collections::HashMap,
+    env,
     ops::Range,
     path::{Path, PathBuf},
-    rc::Rc,
+    sync::Arc,
 };
 
-#[derive(Debug)]
-struct IndexEntry {
-    name: Rc<PathBuf>,
-    start: usize,
-    range: Range<usize>,
+/// Collected code with its index stored in static memory.
+///
+/// It is produced by `include_cl!` macro of `ocl-include-macros` crate.
+pub struct StaticCode {
+    pub code: &'static str,
+    /// Index in binary form including column mappings, see `Index::to_bytes`.
+    pub index: &'static [u8],
 }
 
-/// Index that maps generated code locations to their origins
-pub struct Index {
-    segs: Vec<IndexEntry>,
-    size: usize,
+impl StaticCode {
+    pub fn index(&self) -> Index {
+        Index::from_bytes(self.index).expect("index of static code is invalid")
+    }
 }
 
-impl Index {
-    fn new() -> Self {
-        Self {
-            segs: Vec::new(),
-            size: 0,
-        }
-    }
+/// Format of file names in `#line` directives.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LineNames {
+    /// Paths as they are returned by source.
+    Paths,
+    /// Absolute paths, relative ones are resolved against the current directory.
+    Absolute,
+    /// Paths relative to the specified directory, if possible.
+    Relative(PathBuf),
+    /// Numeric identifiers of files instead of quoted names,
+    /// the identifiers could be resolved by `Index::files`.
+    Ids,
+}
 
-    fn push(&mut self, name: Rc<PathBuf>, start: usize, size: usize) {
-        self.segs.push(IndexEntry {
-            name,
-            start,
-            range: self.size..(self.size + size),
-        });
-        self.size += size;
-    }
+struct LineWriter<'a> {
+    names: &'a LineNames,
+    ids: HashMap<PathBuf, usize>,
+}
 
-    fn append(&mut self, mut index: Index) {
-        for seg in index.segs.iter_mut() {
-            let r = &mut seg.range;
-            r.start += self.size;
-            r.end += self.size;
-        }
-        self.segs.append(&mut index.segs);
-        self.size += index.size;
-    }
-
-    /// Maps line number in generated code to source file name and position in it
-    pub fn search(&self, pos: usize) -> Option<(PathBuf, usize)> {
-        match self.segs.binary_search_by(|seg| {
-            if pos < seg.range.start {
-                Ordering::Greater
-            } else if pos >= seg.range.end {
-                Ordering::Less
-            } else {
-                Ordering::Equal
+impl<'a> LineWriter<'a> {
+    fn write(&mut self, accum: &mut String, name: &Path, line: usize) {
+        let path = match self.names {
+            LineNames::Paths => name.to_path_buf(),
+            LineNames::Absolute => match env::current_dir() {
+                Ok(dir) => dir.join(name),
+                Err(_) => name.to_path_buf(),
+            },
+            LineNames::Relative(base) => name.strip_prefix(base).unwrap_or(name).to_path_buf(),
+            LineNames::Ids => {
+                let len = self.ids.len();
+                let id = *self.ids.entry(name.to_path_buf()).or_insert(len);
+                accum.push_str(&format!("#line {} {}\n", line + 1, id));
+                return;
             }
-        }) {
-            Ok(i) => {
-                let seg = &self.segs[i];
-                Some(((*seg.name).clone(), pos - seg.range.start + seg.start))
-            }
-            Err(_) => None,
-        }
+        };
+        let quoted = path
+            .to_string_lossy()
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"");
+        accum.push_str(&format!("#line {} \"{}\"\n", line + 1, quoted));
     }
 }
 
+/// Escapes path to be used in Makefile rule.
+fn make_escape(path: &Path) -> String {
+    path.to_string_lossy()
+        .replace(' ', "\\ ")
+        .replace('#', "\\#")
+        .replace('$', "$$")
+}
+
 /// Tree of parsed source files
 pub struct Node {
     name: PathBuf,
     inner: Vec<(Node, usize)>,
+    skipped: Vec<(PathBuf, usize)>,
     text: String,
     index: Vec<Range<usize>>,
+    columns: HashMap<usize, ColumnMap>,
 }
 
 impl Node {
@@ -78,8 +88,10 @@ impl Node {
         Self {
             name: name.to_path_buf(),
             inner: Vec::new(),
+            skipped: Vec::new(),
             text: String::new(),
             index: Vec::new(),
+            columns: HashMap::new(),
         }
     }
 
@@ -94,42 +106,152 @@ impl Node {
         self.index.push(plen..self.text.len());
     }
 
+    /// Adds line which content differs from the original one.
+    pub(crate) fn add_mapped_line(&mut self, line: &str, map: ColumnMap) {
+        self.columns.insert(self.index.len(), map);
+        self.add_line(line);
+    }
+
     pub(crate) fn add_child(&mut self, node: Node) {
         self.add_line("");
         self.inner.push((node, self.index.len() - 1));
     }
 
+    pub(crate) fn add_skipped(&mut self, path: PathBuf) {
+        self.add_line("");
+        self.skipped.push((path, self.index.len() - 1));
+    }
+
     pub fn lines_count(&self) -> usize {
         self.index.len()
     }
 
+    /// Lines of the file after preprocessing, without line breaks.
+    ///
+    /// Lines where other files are included are empty.
+    pub fn lines(&self) -> impl Iterator<Item = &str> + '_ {
+        self.index
+            .iter()
+            .map(move |range| &self.text[range.start..(range.end - 1)])
+    }
+
+    /// Included files with the numbers of lines where they are included.
+    pub fn children(&self) -> impl Iterator<Item = (&Node, usize)> + '_ {
+        self.inner.iter().map(|(node, pos)| (node, *pos))
+    }
+
+    /// Files included at the line but skipped because they were already included with `#pragma once`.
+    pub fn skipped(&self) -> impl Iterator<Item = (&Path, usize)> + '_ {
+        self.skipped
+            .iter()
+            .map(|(path, pos)| (path.as_path(), *pos))
+    }
+
+    /// Visits the tree in depth-first order, the visitor receives each node and its depth.
+    pub fn visit<F: FnMut(&Node, usize)>(&self, mut visitor: F) {
+        self.visit_inner(&mut visitor, 0);
+    }
+
+    fn visit_inner<F: FnMut(&Node, usize)>(&self, visitor: &mut F, depth: usize) {
+        visitor(self, depth);
+        for (node, _) in self.inner.iter() {
+            node.visit_inner(visitor, depth + 1);
+        }
+    }
+
+    /// All files of the tree in order of inclusion, each file is listed once.
+    ///
+    /// Unlike `Index::files` it also contains files that produced no lines of code.
+    pub fn files(&self) -> Vec<PathBuf> {
+        let mut files = Vec::new();
+        self.visit(|node, _| {
+            if !files.contains(&node.name) {
+                files.push(node.name.clone());
+            }
+        });
+        files
+    }
+
+    /// Generates Makefile rule stating that `target` depends on all files of the tree.
+    ///
+    /// Like `gcc -MP` it also adds phony targets for included files,
+    /// so the build does not fail when some header is removed.
+    pub fn depfile(&self, target: &Path) -> String {
+        let files = self.files();
+        let mut text = make_escape(target);
+        text.push(':');
+        for file in files.iter() {
+            text.push(' ');
+            text.push_str(&make_escape(file));
+        }
+        text.push('\n');
+        for file in files.iter().skip(1) {
+            text.push_str(&format!("\n{}:\n", make_escape(file)));
+        }
+        text
+    }
+
     /// Generates resulting code string and mapping index for it
     pub fn collect(&self) -> (String, Index) {
         let mut accum = String::new();
         let mut index = Index::new();
+        self.collect_into(&mut accum, &mut index, None);
+        (accum, index)
+    }
 
-        let name = Rc::new(self.name.clone());
-
-        let mut ppos = 0;
-        for (node, pos) in self.inner.iter() {
-            let start = self.index[ppos].start;
-            let end = self.index[*pos].end;
+    /// Generates resulting code string with `#line` directives inserted
+    /// at each place where the source file changes.
+    ///
+    /// Lines of directives are mapped by index to the lines following them.
+    pub fn collect_with_lines(&self, names: &LineNames) -> (String, Index) {
+        let mut accum = String::new();
+        let mut index = Index::new();
+        let mut writer = LineWriter {
+            names,
+            ids: HashMap::new(),
+        };
+        self.collect_into(&mut accum, &mut index, Some(&mut writer));
+        (accum, index)
+    }
 
-            accum.push_str(&self.text[start..end]);
-            index.push(name.clone(), ppos, pos - ppos + 1);
+    fn push_segment(
+        &self,
+        name: &Arc<PathBuf>,
+        accum: &mut String,
+        index: &mut Index,
+        lines: &mut Option<&mut LineWriter>,
+        range: Range<usize>,
+    ) {
+        if let Some(writer) = lines {
+            writer.write(accum, &self.name, range.start);
+            index.push_directive(name.clone(), range.start);
+        }
+        accum.push_str(&self.text[self.index[range.start].start..self.index[range.end - 1].end]);
+        for pos in range.clone() {
+            if let Some(map) = self.columns.get(&pos) {
+                index.push_columns(index.len() + pos - range.start, map.clone());
+            }
+        }
+        index.push(name.clone(), range.start, range.end - range.start);
+    }
 
-            let (child_str, child_index) = node.collect();
-            accum.push_str(&child_str);
-            index.append(child_index);
+    fn collect_into(
+        &self,
+        accum: &mut String,
+        index: &mut Index,
+        mut lines: Option<&mut LineWriter>,
+    ) {
+        let name = Arc::new(self.name.clone());
 
+        let mut ppos = 0;
+        for (node, pos) in self.inner.iter() {
+            self.push_segment(&name, accum, index, &mut lines, ppos..(pos + 1));
+            node.collect_into(accum, index, lines.as_deref_mut());
             ppos = *pos + 1;
         }
 
         if ppos < self.index.len() {
-            accum.push_str(&self.text[self.index[ppos].start..]);
-            index.push(name, ppos, self.index.len() - ppos);
+            self.push_segment(&name, accum, index, &mut lines, ppos..self.index.len());
         }
-
-        (accum, index)
     }
 }
@@ -1,11 +1,16 @@
 use super::{
+    expr::Symbol,
     file_context::FileContext,
-    parser::{FileCache, FileCacheEntry, Flags},
+    macros::{Macro, Macros},
+    parser::{FileCache, FileCacheEntry, Options},
+};
+use crate::{
+    error::Error,
+    node::Node,
+    source::{normalize, Source},
 };
-use crate::{node::Node, source::Source};
 use std::{
     collections::hash_map::Entry,
-    io,
     path::{Path, PathBuf},
 };
 
@@ -13,61 +18,118 @@ pub struct Context<'a> {
     source: &'a dyn Source,
     file_cache: &'a mut FileCache,
     file_stack: Vec<PathBuf>,
-    flags: &'a Flags,
+    macros: Macros,
+    options: &'a Options,
 }
 
 impl<'a> Context<'a> {
-    pub fn new(source: &'a dyn Source, flags: &'a Flags, file_cache: &'a mut FileCache) -> Self {
+    pub fn new(
+        source: &'a dyn Source,
+        macros: Macros,
+        options: &'a Options,
+        file_cache: &'a mut FileCache,
+    ) -> Self {
         Self {
             source,
             file_cache,
             file_stack: Vec::new(),
-            flags,
+            macros,
+            options,
         }
     }
 
-    pub fn flags(&self) -> &'a Flags {
-        self.flags
+    pub fn into_macros(self) -> Macros {
+        self.macros
+    }
+
+    pub fn options(&self) -> &'a Options {
+        self.options
+    }
+    pub fn macros(&self) -> &Macros {
+        &self.macros
+    }
+    /// Records definition met in the code.
+    pub fn define(&mut self, name: &str, value: Macro) {
+        self.macros.insert(String::from(name), Some(value));
+    }
+    /// Records `#undef` met in the code.
+    pub fn undefine(&mut self, name: &str) {
+        self.macros.insert(String::from(name), None);
+    }
+    /// Makes the name unknown, e.g. when it is defined inside unresolved gate.
+    pub fn forget(&mut self, name: &str) {
+        self.macros.remove(name);
     }
+    /// Returns information about the name if it is known.
+    pub fn symbol(&self, name: &str) -> Option<Symbol<'_>> {
+        self.macros.get(name).map(|value| match value {
+            Some(m) if m.params.is_none() && !m.body.is_empty() => Symbol::Defined(Some(&m.body)),
+            Some(_) => Symbol::Defined(None),
+            None => Symbol::Undefined,
+        })
+    }
+
     pub fn is_file_occured(&self, path: &Path) -> bool {
         self.file_cache.get(path).unwrap().occured > 1
     }
 
-    fn read_file(&mut self, path: &Path, dir: Option<&Path>) -> io::Result<(PathBuf, String)> {
-        self.source.read(path, dir).map(|(path, text)| {
-            match self.file_cache.entry(path.clone()) {
-                Entry::Occupied(mut v) => {
-                    v.get_mut().occured += 1;
+    fn read_file(&mut self, path: &Path, dir: Option<&Path>) -> Result<(PathBuf, String), Error> {
+        let res = if self.file_stack.is_empty() {
+            self.source.read_main(path)
+        } else {
+            self.source.read(path, dir)
+        };
+        res.map_err(|e| Error::from_io(path, e))
+            .map(|(path, text)| {
+                // Sources may return the same file under different names.
+                let path = normalize(&path);
+                match self.file_cache.entry(path.clone()) {
+                    Entry::Occupied(mut v) => {
+                        v.get_mut().occured += 1;
+                    }
+                    Entry::Vacant(v) => {
+                        v.insert(FileCacheEntry::new());
+                    }
                 }
-                Entry::Vacant(v) => {
-                    v.insert(FileCacheEntry::new());
-                }
-            }
-            (path, text)
-        })
+                (path, text)
+            })
     }
 
-    fn parse_file(&mut self, path: &Path, text: String) -> io::Result<Option<Node>> {
-        FileContext::new(path, self).parse(text)
+    fn parse_file(
+        &mut self,
+        path: &Path,
+        text: String,
+        certain: bool,
+    ) -> Result<Option<Node>, Error> {
+        FileContext::new(path, self, certain).parse(text)
     }
 
-    pub fn build_tree(&mut self, path: &Path, dir: Option<&Path>) -> io::Result<Option<Node>> {
+    /// Returns the resolved path of the file and its tree,
+    /// or `None` instead of the tree if the file is skipped because of `#pragma once`.
+    ///
+    /// If the include is not `certain` to be reached, definitions in the file make names unknown.
+    pub fn build_tree(
+        &mut self,
+        path: &Path,
+        dir: Option<&Path>,
+        certain: bool,
+    ) -> Result<(PathBuf, Option<Node>), Error> {
         self.read_file(path, dir)
             .and_then(|(path, text)| {
                 if self.file_stack.iter().filter(|p| **p == path).count() >= 2 {
-                    Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "recursion found",
-                    ))
+                    Err(Error::Recursion {
+                        path,
+                        location: None,
+                    })
                 } else {
                     self.file_stack.push(path.clone());
                     Ok((path, text))
                 }
             })
-            .and_then(|(path, text)| self.parse_file(&path, text).map(|x| (x, path)))
+            .and_then(|(path, text)| self.parse_file(&path, text, certain).map(|x| (x, path)))
             .map(|(x, path)| {
                 assert_eq!(self.file_stack.pop().unwrap(), path);
-                x
+                (path, x)
             })
     }
 }
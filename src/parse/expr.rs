@@ -0,0 +1,360 @@
+/// Information about a name used in expression.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Symbol<'a> {
+    /// Name is known to be not defined.
+    Undefined,
+    /// Name is defined, its value is provided if it is known.
+    Defined(Option<&'a str>),
+}
+
+/// Integer value of expression, either `intmax_t` or `uintmax_t` like in C.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Value {
+    Signed(i64),
+    Unsigned(u64),
+}
+
+impl Value {
+    fn from_bool(x: bool) -> Self {
+        Value::Signed(x as i64)
+    }
+
+    fn is_true(self) -> bool {
+        self != Value::Signed(0) && self != Value::Unsigned(0)
+    }
+
+    fn to_unsigned(self) -> u64 {
+        match self {
+            Value::Signed(x) => x as u64,
+            Value::Unsigned(x) => x,
+        }
+    }
+
+    /// Converts both operands to the common type.
+    fn common(a: Self, b: Self) -> (Self, Self) {
+        match (a, b) {
+            (Value::Signed(_), Value::Signed(_)) => (a, b),
+            _ => (
+                Value::Unsigned(a.to_unsigned()),
+                Value::Unsigned(b.to_unsigned()),
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Num(Option<Value>),
+    Ident(String),
+    Punct(&'static str),
+}
+
+const PUNCTS: [&str; 24] = [
+    "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "(", ")", "!", "~", "*", "/", "%", "+", "-",
+    "<", ">", "&", "^", "|", "?", ":",
+];
+
+/// Maximum depth of nested name substitution.
+const MAX_DEPTH: usize = 64;
+
+/// Parses integer literal, it is unsigned if it has `u` suffix or does not fit into signed type.
+fn parse_number(text: &str) -> Option<Value> {
+    let digits = text.trim_end_matches(['u', 'U', 'l', 'L']);
+    let unsigned = text[digits.len()..].contains(['u', 'U']);
+    let value = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        u64::from_str_radix(hex, 16).ok()?
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        u64::from_str_radix(&digits[1..], 8).ok()?
+    } else {
+        digits.parse::<u64>().ok()?
+    };
+    Some(if unsigned || value > i64::MAX as u64 {
+        Value::Unsigned(value)
+    } else {
+        Value::Signed(value as i64)
+    })
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, ()> {
+    let mut tokens = Vec::new();
+    let mut rest = text;
+    loop {
+        rest = rest.trim_start();
+        if rest.is_empty() || rest.starts_with("//") {
+            break;
+        }
+        if let Some(comment) = rest.strip_prefix("/*") {
+            rest = &comment[(comment.find("*/").ok_or(())? + 2)..];
+            continue;
+        }
+        let c = rest.chars().next().unwrap();
+        if c.is_ascii_digit() {
+            let len = rest
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .unwrap_or(rest.len());
+            tokens.push(Token::Num(Some(parse_number(&rest[..len]).ok_or(())?)));
+            rest = &rest[len..];
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let len = rest
+                .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Ident(String::from(&rest[..len])));
+            rest = &rest[len..];
+        } else {
+            let punct = PUNCTS.iter().find(|p| rest.starts_with(**p)).ok_or(())?;
+            tokens.push(Token::Punct(punct));
+            rest = &rest[punct.len()..];
+        }
+    }
+    Ok(tokens)
+}
+
+struct Expander<'a, F: Fn(&str) -> Option<Symbol<'a>>> {
+    lookup: F,
+    stack: Vec<String>,
+}
+
+impl<'a, F: Fn(&str) -> Option<Symbol<'a>>> Expander<'a, F> {
+    /// Replaces `defined` operators and names with their values.
+    fn expand(&mut self, tokens: Vec<Token>, out: &mut Vec<Token>) -> Result<(), ()> {
+        let mut iter = tokens.into_iter();
+        while let Some(token) = iter.next() {
+            match token {
+                Token::Ident(name) if name == "defined" => {
+                    let name = match iter.next() {
+                        Some(Token::Ident(name)) => name,
+                        Some(Token::Punct("(")) => match (iter.next(), iter.next()) {
+                            (Some(Token::Ident(name)), Some(Token::Punct(")"))) => name,
+                            _ => return Err(()),
+                        },
+                        _ => return Err(()),
+                    };
+                    out.push(Token::Num(match (self.lookup)(&name) {
+                        Some(Symbol::Undefined) => Some(Value::from_bool(false)),
+                        Some(Symbol::Defined(_)) => Some(Value::from_bool(true)),
+                        None => None,
+                    }));
+                }
+                Token::Ident(name) => match (self.lookup)(&name) {
+                    Some(Symbol::Undefined) => out.push(Token::Num(Some(Value::Signed(0)))),
+                    Some(Symbol::Defined(Some(value)))
+                        if !self.stack.contains(&name) && self.stack.len() < MAX_DEPTH =>
+                    {
+                        let value_tokens = tokenize(value)?;
+                        self.stack.push(name);
+                        let res = self.expand(value_tokens, out);
+                        self.stack.pop();
+                        res?;
+                    }
+                    _ => out.push(Token::Num(None)),
+                },
+                token => out.push(token),
+            }
+        }
+        Ok(())
+    }
+}
+
+fn binary_priority(op: &str) -> Option<usize> {
+    Some(match op {
+        "||" => 1,
+        "&&" => 2,
+        "|" => 3,
+        "^" => 4,
+        "&" => 5,
+        "==" | "!=" => 6,
+        "<" | "<=" | ">" | ">=" => 7,
+        "<<" | ">>" => 8,
+        "+" | "-" => 9,
+        "*" | "/" | "%" => 10,
+        _ => return None,
+    })
+}
+
+fn apply_binary(op: &str, a: Option<Value>, b: Option<Value>) -> Option<Value> {
+    match op {
+        // Logical operators may be known even if one of operands is unknown.
+        "||" => match (a, b) {
+            (Some(x), _) | (_, Some(x)) if x.is_true() => Some(Value::from_bool(true)),
+            (Some(_), Some(_)) => Some(Value::from_bool(false)),
+            _ => None,
+        },
+        "&&" => match (a, b) {
+            (Some(x), _) | (_, Some(x)) if !x.is_true() => Some(Value::from_bool(false)),
+            (Some(_), Some(_)) => Some(Value::from_bool(true)),
+            _ => None,
+        },
+        // Result of shift has the type of the left operand, too large shifts are undefined.
+        "<<" | ">>" => {
+            let (a, b) = (a?, b?);
+            let shift = match b {
+                Value::Signed(x) if (0..64).contains(&x) => x as u32,
+                Value::Unsigned(x) if x < 64 => x as u32,
+                _ => return None,
+            };
+            Some(match (op, a) {
+                ("<<", Value::Signed(x)) => Value::Signed(x << shift),
+                ("<<", Value::Unsigned(x)) => Value::Unsigned(x << shift),
+                (_, Value::Signed(x)) => Value::Signed(x >> shift),
+                (_, Value::Unsigned(x)) => Value::Unsigned(x >> shift),
+            })
+        }
+        _ => {
+            let (a, b) = Value::common(a?, b?);
+            // Wrapping operations give the same bits for signed and unsigned values.
+            let (x, y) = (a.to_unsigned(), b.to_unsigned());
+            let wrap = |z: u64| match a {
+                Value::Signed(_) => Value::Signed(z as i64),
+                Value::Unsigned(_) => Value::Unsigned(z),
+            };
+            let ord = match (a, b) {
+                (Value::Signed(a), Value::Signed(b)) => a.cmp(&b),
+                _ => x.cmp(&y),
+            };
+            Some(match op {
+                "|" => wrap(x | y),
+                "^" => wrap(x ^ y),
+                "&" => wrap(x & y),
+                "==" => Value::from_bool(ord.is_eq()),
+                "!=" => Value::from_bool(ord.is_ne()),
+                "<" => Value::from_bool(ord.is_lt()),
+                "<=" => Value::from_bool(ord.is_le()),
+                ">" => Value::from_bool(ord.is_gt()),
+                ">=" => Value::from_bool(ord.is_ge()),
+                "+" => wrap(x.wrapping_add(y)),
+                "-" => wrap(x.wrapping_sub(y)),
+                "*" => wrap(x.wrapping_mul(y)),
+                "/" | "%" => match (a, b) {
+                    (Value::Signed(a), Value::Signed(b)) if op == "/" => {
+                        Value::Signed(a.checked_div(b)?)
+                    }
+                    (Value::Signed(a), Value::Signed(b)) => Value::Signed(a.checked_rem(b)?),
+                    _ if op == "/" => Value::Unsigned(x.checked_div(y)?),
+                    _ => Value::Unsigned(x.checked_rem(y)?),
+                },
+                _ => unreachable!(),
+            })
+        }
+    }
+}
+
+struct Calculator {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Calculator {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), ()> {
+        match self.next() {
+            Some(Token::Punct(p)) if p == punct => Ok(()),
+            _ => Err(()),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Option<Value>, ()> {
+        match self.next().ok_or(())? {
+            Token::Num(value) => Ok(value),
+            Token::Punct("(") => {
+                let value = self.ternary()?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            Token::Punct(op @ ("!" | "~" | "-" | "+")) => {
+                let value = self.primary()?;
+                Ok(value.map(|x| match (op, x) {
+                    ("!", x) => Value::from_bool(!x.is_true()),
+                    ("~", Value::Signed(x)) => Value::Signed(!x),
+                    ("~", Value::Unsigned(x)) => Value::Unsigned(!x),
+                    ("-", Value::Signed(x)) => Value::Signed(x.wrapping_neg()),
+                    ("-", Value::Unsigned(x)) => Value::Unsigned(x.wrapping_neg()),
+                    (_, x) => x,
+                }))
+            }
+            _ => Err(()),
+        }
+    }
+
+    fn binary(&mut self, min_priority: usize) -> Result<Option<Value>, ()> {
+        let mut lhs = self.primary()?;
+        while let Some(&Token::Punct(op)) = self.peek() {
+            let priority = match binary_priority(op) {
+                Some(p) if p >= min_priority => p,
+                _ => break,
+            };
+            self.pos += 1;
+            let rhs = self.binary(priority + 1)?;
+            lhs = apply_binary(op, lhs, rhs);
+        }
+        Ok(lhs)
+    }
+
+    fn ternary(&mut self) -> Result<Option<Value>, ()> {
+        let cond = self.binary(1)?;
+        if let Some(Token::Punct("?")) = self.peek() {
+            self.pos += 1;
+            let a = self.ternary()?;
+            self.expect(":")?;
+            let b = self.ternary()?;
+            // Result has the common type of both alternatives.
+            let (a, b) = match (a, b) {
+                (Some(a), Some(b)) => {
+                    let (a, b) = Value::common(a, b);
+                    (Some(a), Some(b))
+                }
+                _ => (a, b),
+            };
+            Ok(match cond {
+                Some(c) => {
+                    if c.is_true() {
+                        a
+                    } else {
+                        b
+                    }
+                }
+                None if a.is_some() && a == b => a,
+                None => None,
+            })
+        } else {
+            Ok(cond)
+        }
+    }
+}
+
+/// Evaluates preprocessor condition expression.
+///
+/// Names are resolved with `lookup` function that returns `None` for unknown names.
+/// Returns `None` if expression is malformed or its value depends on unknown names,
+/// otherwise returns whether the value is non-zero.
+///
+/// Values are signed or unsigned 64-bit integers converted like `intmax_t` and `uintmax_t` in C.
+pub fn evaluate<'a, F: Fn(&str) -> Option<Symbol<'a>>>(expr: &str, lookup: F) -> Option<bool> {
+    let mut expander = Expander {
+        lookup,
+        stack: Vec::new(),
+    };
+    let mut tokens = Vec::new();
+    tokenize(expr)
+        .and_then(|raw| expander.expand(raw, &mut tokens))
+        .ok()?;
+
+    let mut calc = Calculator { tokens, pos: 0 };
+    let value = calc.ternary().ok()?;
+    if calc.pos == calc.tokens.len() {
+        value.map(Value::is_true)
+    } else {
+        None
+    }
+}
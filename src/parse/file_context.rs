@@ -1,11 +1,20 @@
 use super::{
     context::Context,
-    gate::{Gate, GateStack},
+    expr::evaluate,
+    gate::{Directive, Gate, GateStack},
+    macros::{expand_line, is_incomplete, strip_comments, Macro},
+};
+use crate::{
+    error::{Error, Location},
+    index::ColumnMap,
+    node::Node,
 };
-use crate::node::Node;
 use lazy_static::lazy_static;
 use regex::{Regex, RegexBuilder};
-use std::{io, path::Path};
+use std::{
+    borrow::Cow,
+    path::{Path, PathBuf},
+};
 
 fn make_regex(expr: &str) -> Regex {
     RegexBuilder::new(expr).multi_line(true).build().unwrap()
@@ -14,54 +23,205 @@ fn make_regex(expr: &str) -> Regex {
 lazy_static! {
     static ref INCLUDE: Regex = make_regex(r#"^\s*#include\s*([<"])(.*)([>"])\s*(?://)?.*$"#);
     static ref PRAGMA_ONCE: Regex = make_regex(r#"^\s*#pragma\s+once\s*(?://)?.*$"#);
-    static ref IFDEF: Regex = make_regex(r#"^\s*#if(n?)def\s+(.*)\s*(?://)?.*$"#);
-    static ref IF: Regex = make_regex(r#"^\s*#if\s.*$"#);
+    static ref IFDEF: Regex = make_regex(r#"^\s*#if(n?)def\s+(\w+).*$"#);
+    static ref IF: Regex = make_regex(r#"^\s*#if\s+(.*)$"#);
+    static ref ELIF: Regex = make_regex(r#"^\s*#elif\s+(.*)$"#);
     static ref ELSE: Regex = make_regex(r#"^\s*#else\s*(?://)?.*$"#);
     static ref ENDIF: Regex = make_regex(r#"^\s*#endif\s*(?://)?.*$"#);
+    static ref DEFINE: Regex = make_regex(r#"^\s*#define\s+(\w+)(\([^)]*\))?(.*)$"#);
+    static ref UNDEF: Regex = make_regex(r#"^\s*#undef\s+(\w+).*$"#);
+}
+
+/// Joins directive line with its continuation lines.
+///
+/// Returns the resulting line and the number of lines joined.
+fn join_lines<'c>(lines: &[&'c str]) -> (Cow<'c, str>, Vec<usize>) {
+    let mut line = Cow::Borrowed(lines[0]);
+    let mut starts = vec![0];
+    if line.trim_start().starts_with('#') {
+        while starts.len() < lines.len() {
+            let head = match line.trim_end().strip_suffix('\\') {
+                Some(head) => head,
+                None => break,
+            };
+            starts.push(head.len());
+            line = Cow::Owned(format!("{}{}", head, lines[starts.len() - 1]));
+        }
+    }
+    (line, starts)
 }
 
 enum ParseLine<'a> {
     Empty,
-    Text(&'a str),
+    Text(Cow<'a, str>),
+    /// Changed text with its chunks, see `expand_line`.
+    Mapped(String, Vec<(usize, usize, bool)>),
     Node(Node),
+    /// Include of the file skipped because of `#pragma once`.
+    Skipped(PathBuf),
     Break,
-    Err(io::Error),
+    Err(Error),
 }
 
 pub struct FileContext<'a, 'b> {
     node: Node,
     context: &'b mut Context<'a>,
     gate_stack: GateStack,
+    in_comment: bool,
 }
 
 impl<'a, 'b> FileContext<'a, 'b> {
-    pub fn new(path: &Path, context: &'b mut Context<'a>) -> Self {
+    /// Creates context of the file, `certain` tells whether its include is known to be reached.
+    pub fn new(path: &Path, context: &'b mut Context<'a>, certain: bool) -> Self {
         Self {
             node: Node::new(path),
             context,
-            gate_stack: GateStack::new(),
+            gate_stack: GateStack::new(certain),
+            in_comment: false,
         }
     }
 
-    pub fn parse(mut self, text: String) -> io::Result<Option<Node>> {
-        for line in text.lines() {
-            match self.parse_line(line) {
+    pub fn parse(mut self, text: String) -> Result<Option<Node>, Error> {
+        let lines = text.lines().collect::<Vec<_>>();
+        let mut pos = 0;
+        while pos < lines.len() {
+            let (mut line, mut starts) = join_lines(&lines[pos..]);
+            if self.is_incomplete(&line) {
+                if let Some((joined, joined_starts)) = self.join_invocation(&line, &lines[pos..]) {
+                    line = Cow::Owned(joined);
+                    starts = joined_starts;
+                }
+            }
+            let count = starts.len();
+            let physical = &lines[pos..(pos + count)];
+            pos += count;
+            match self.parse_line(&line) {
                 ParseLine::Empty => {
                     self.node.add_line("");
                 }
                 ParseLine::Text(text) => {
-                    self.node.add_line(text);
+                    if *text == *line {
+                        // Keep the original line breaks.
+                        for phys_line in physical {
+                            self.node.add_line(phys_line);
+                        }
+                        continue;
+                    }
+                    self.node.add_line(&text);
+                }
+                ParseLine::Mapped(text, chunks) => {
+                    if count == 1 {
+                        starts.clear();
+                    }
+                    self.node.add_mapped_line(
+                        &text,
+                        ColumnMap {
+                            chunks,
+                            lines: starts,
+                        },
+                    );
                 }
                 ParseLine::Node(child_node) => {
                     self.node.add_child(child_node);
                 }
+                ParseLine::Skipped(path) => {
+                    self.node.add_skipped(path);
+                }
                 ParseLine::Break => return Ok(None),
                 ParseLine::Err(e) => return Err(e),
             }
+            for _ in 1..count {
+                self.node.add_line("");
+            }
         }
         Ok(Some(self.node))
     }
 
+    /// Joins the line with the following ones until macro invocation in it is closed.
+    ///
+    /// Returns `None` if the invocation is not closed before the next directive or the end of file,
+    /// then the line is left as is.
+    fn join_invocation(&self, line: &str, lines: &[&str]) -> Option<(String, Vec<usize>)> {
+        let mut line = String::from(line);
+        let mut starts = vec![0];
+        for next in lines[1..].iter() {
+            if next.trim_start().starts_with('#') {
+                return None;
+            }
+            starts.push(line.len() + 1);
+            line = format!("{}\n{}", line, next);
+            if !self.is_incomplete(&line) {
+                return Some((line, starts));
+            }
+        }
+        None
+    }
+
+    fn is_incomplete(&self, line: &str) -> bool {
+        self.context.options().expand
+            && self.gate_stack.is_open()
+            && !line.trim_start().starts_with('#')
+            && is_incomplete(line, self.context.macros(), self.in_comment)
+    }
+
+    fn ifdef_gate(&self, name: &str, defined: bool) -> Gate {
+        match self.context.macros().get(name) {
+            Some(value) => Gate::Known(defined == value.is_some()),
+            None => Gate::Unknown,
+        }
+    }
+
+    fn if_gate(&self, expr: &str) -> Gate {
+        match evaluate(expr, |name| self.context.symbol(name)) {
+            Some(value) => Gate::Known(value),
+            None => Gate::Unknown,
+        }
+    }
+
+    fn define<'c>(&mut self, name: &str, value: Option<Macro>, line: &'c str) -> ParseLine<'c> {
+        if self.gate_stack.is_certain() {
+            match value {
+                Some(value) => self.context.define(name, value),
+                None => self.context.undefine(name),
+            }
+            if self.context.options().keep_defines {
+                ParseLine::Text(line.into())
+            } else {
+                ParseLine::Empty
+            }
+        } else {
+            // We don't know whether the directive takes effect.
+            self.context.forget(name);
+            ParseLine::Text(line.into())
+        }
+    }
+
+    /// Location of the directive in the current line.
+    fn location(&self, line: &str) -> Location {
+        let column = line.find('#').unwrap_or(0);
+        Location::new(self.node.name(), self.node.lines_count(), column)
+    }
+
+    fn directive<'c>(&self, dir: Directive, line: &'c str) -> ParseLine<'c> {
+        match dir {
+            Directive::Skip => ParseLine::Empty,
+            Directive::Keep => ParseLine::Text(line.into()),
+            Directive::AsIf => {
+                let start = line.find("#elif").unwrap();
+                let chunks = vec![
+                    (0, 0, true),
+                    (start, start, false),
+                    (start + 3, start + 5, true),
+                ];
+                ParseLine::Mapped(line.replacen("#elif", "#if", 1), chunks)
+            }
+            Directive::AsElse => {
+                let indent = &line[..line.find('#').unwrap()];
+                ParseLine::Text(format!("{}#else", indent).into())
+            }
+        }
+    }
+
     fn parse_line<'c>(&mut self, line: &'c str) -> ParseLine<'c> {
         let path = self.node.name().to_path_buf();
         if PRAGMA_ONCE.is_match(line) {
@@ -71,65 +231,45 @@ impl<'a, 'b> FileContext<'a, 'b> {
                 ParseLine::Empty
             }
         } else if let Some(cap) = IFDEF.captures(line) {
-            let name = &cap[2];
-            match self.context.flags().get(name) {
-                Some(&flag_value) => {
-                    let value = cap[1].is_empty();
-                    self.gate_stack
-                        .push(Gate::Known(String::from(name), value == flag_value));
-                    ParseLine::Empty
-                }
-                None => {
-                    self.gate_stack.push(Gate::Unknown);
-                    if self.gate_stack.is_open() {
-                        ParseLine::Text(line)
-                    } else {
-                        ParseLine::Empty
-                    }
-                }
-            }
-        } else if IF.is_match(line) {
-            self.gate_stack.push(Gate::Unknown);
-            if self.gate_stack.is_open() {
-                ParseLine::Text(line)
-            } else {
-                ParseLine::Empty
+            let dir = self
+                .gate_stack
+                .push(self.ifdef_gate(&cap[2], cap[1].is_empty()));
+            self.directive(dir, line)
+        } else if let Some(cap) = IF.captures(line) {
+            let dir = self.gate_stack.push(self.if_gate(&cap[1]));
+            self.directive(dir, line)
+        } else if let Some(cap) = ELIF.captures(line) {
+            match self.gate_stack.next(self.if_gate(&cap[1])) {
+                Ok(dir) => self.directive(dir, line),
+                Err(()) => ParseLine::Err(Error::UnexpectedElif {
+                    location: self.location(line),
+                }),
             }
         } else if ELSE.is_match(line) {
-            match self.gate_stack.invert_last() {
-                Ok(known) => {
-                    if known {
-                        ParseLine::Empty
-                    } else if self.gate_stack.is_open() {
-                        ParseLine::Text(line)
-                    } else {
-                        ParseLine::Empty
-                    }
-                }
-                Err(()) => ParseLine::Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "Unexpected #else",
-                )),
+            match self.gate_stack.last() {
+                Ok(dir) => self.directive(dir, line),
+                Err(()) => ParseLine::Err(Error::UnexpectedElse {
+                    location: self.location(line),
+                }),
             }
         } else if ENDIF.is_match(line) {
             match self.gate_stack.pop() {
-                Some(gate) => match gate {
-                    Gate::Known(_, _) => ParseLine::Empty,
-                    Gate::Unknown => {
-                        if self.gate_stack.is_open() {
-                            ParseLine::Text(line)
-                        } else {
-                            ParseLine::Empty
-                        }
-                    }
-                },
-                None => ParseLine::Err(io::Error::new(
-                    io::ErrorKind::InvalidData,
-                    "Unexpected #endif",
-                )),
+                Ok(dir) => self.directive(dir, line),
+                Err(()) => ParseLine::Err(Error::UnexpectedEndif {
+                    location: self.location(line),
+                }),
             }
         } else if self.gate_stack.is_open() {
-            if let Some(cap) = INCLUDE.captures(line) {
+            if let Some(cap) = DEFINE.captures(line) {
+                let body = String::from(strip_comments(&cap[3]).trim());
+                let value = match cap.get(2) {
+                    Some(params) => Macro::function(params.as_str(), body),
+                    None => Macro::object(body),
+                };
+                self.define(&cap[1], Some(value), line)
+            } else if let Some(cap) = UNDEF.captures(line) {
+                self.define(&cap[1], None, line)
+            } else if let Some(cap) = INCLUDE.captures(line) {
                 let inc_path = Path::new(&cap[2]);
                 let (lb, rb) = (&cap[1], &cap[3]);
                 let inc_res = {
@@ -138,33 +278,42 @@ impl<'a, 'b> FileContext<'a, 'b> {
                     } else if lb == "\"" && rb == "\"" {
                         Ok(Some(path.parent().unwrap().to_path_buf()))
                     } else {
-                        Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "bad #include syntax",
-                        ))
+                        Err(Error::BadInclude {
+                            location: self.location(line),
+                        })
                     }
-                    .and_then(|dir_opt| self.context.build_tree(inc_path, dir_opt.as_deref()))
-                    .map_err(|err| {
-                        io::Error::new(
-                            err.kind(),
-                            format!(
-                                "{}\nin file {:?} at line {}",
-                                err,
-                                path,
-                                self.node.lines_count(),
-                            ),
-                        )
+                    .and_then(|dir_opt| {
+                        let certain = self.gate_stack.is_certain();
+                        self.context
+                            .build_tree(inc_path, dir_opt.as_deref(), certain)
+                            .map_err(|err| {
+                                err.included_from(
+                                    &path,
+                                    self.node.lines_count(),
+                                    cap.get(2).unwrap().start(),
+                                )
+                            })
                     })
                 };
                 match inc_res {
-                    Ok(node_opt) => match node_opt {
+                    Ok((inc_path, node_opt)) => match node_opt {
                         Some(node) => ParseLine::Node(node),
-                        None => ParseLine::Empty,
+                        None => ParseLine::Skipped(inc_path),
                     },
                     Err(err) => ParseLine::Err(err),
                 }
+            } else if self.context.options().expand || self.context.options().substitute {
+                match expand_line(
+                    line,
+                    self.context.macros(),
+                    self.context.options().expand,
+                    &mut self.in_comment,
+                ) {
+                    (Cow::Owned(text), chunks) => ParseLine::Mapped(text, chunks),
+                    (text, _) => ParseLine::Text(text),
+                }
             } else {
-                ParseLine::Text(line)
+                ParseLine::Text(line.into())
             }
         } else {
             ParseLine::Empty
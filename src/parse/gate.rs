@@ -1,80 +1,152 @@
+/// Condition of a single branch.
 pub enum Gate {
-    Known(String, bool),
+    Known(bool),
     Unknown,
 }
 
-impl Gate {
-    pub fn is_open(&self) -> bool {
-        match self {
-            Gate::Known(_, value) => *value,
-            Gate::Unknown => true,
-        }
-    }
+/// What should be done with the directive line.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Directive {
+    /// Remove the line from output.
+    Skip,
+    /// Keep the line as is.
+    Keep,
+    /// Line should become an opening `#if` directive,
+    /// because all previous branches were removed.
+    AsIf,
+    /// Line should become an `#else` directive,
+    /// because the branch condition is known to be true.
+    AsElse,
 }
 
+/// State of `#if ... #elif ... #else ... #endif` chain.
 struct GateState {
-    pub gate: Gate,
-    pub inverted: bool,
+    /// Current branch is open.
+    open: bool,
+    /// Current branch is known to be open.
+    certain: bool,
+    /// Some branch of the chain is known to be taken, so the rest ones are closed.
+    taken: bool,
+    /// Some directive of the chain is kept in output.
+    emitted: bool,
+    /// `#else` has been already met.
+    finished: bool,
 }
 
 pub struct GateStack {
     stack: Vec<GateState>,
     state: bool,
+    certain: bool,
+    /// The file is included from a place that is known to be reached.
+    outer_certain: bool,
 }
 
 impl Default for GateStack {
     fn default() -> Self {
-        Self {
-            stack: Vec::new(),
-            state: true,
-        }
+        Self::new(true)
     }
 }
 
 impl GateStack {
-    pub fn new() -> Self {
-        Self::default()
+    /// Creates stack for the file included from the place that is `certain` to be reached or not.
+    pub fn new(certain: bool) -> Self {
+        Self {
+            stack: Vec::new(),
+            state: true,
+            certain,
+            outer_certain: certain,
+        }
     }
     fn compute_state(&mut self) {
-        self.state = self.stack.iter().all(|gs| gs.gate.is_open())
+        self.state = self.stack.iter().all(|gs| gs.open);
+        self.certain = self.state && self.outer_certain && self.stack.iter().all(|gs| gs.certain);
+    }
+    fn outer_open(&self) -> bool {
+        let n = self.stack.len();
+        self.stack[..(n - 1)].iter().all(|gs| gs.open)
+    }
+    fn branch(&mut self, gate: Gate) -> Directive {
+        let outer = self.outer_open();
+        let last = self.stack.last_mut().unwrap();
+        let dir = if !outer || last.taken {
+            last.open = false;
+            Directive::Skip
+        } else {
+            match gate {
+                Gate::Known(false) => {
+                    last.open = false;
+                    Directive::Skip
+                }
+                Gate::Known(true) => {
+                    last.open = true;
+                    last.certain = !last.emitted;
+                    last.taken = true;
+                    if last.emitted {
+                        Directive::AsElse
+                    } else {
+                        Directive::Skip
+                    }
+                }
+                Gate::Unknown => {
+                    last.open = true;
+                    last.certain = false;
+                    if last.emitted {
+                        Directive::Keep
+                    } else {
+                        last.emitted = true;
+                        Directive::AsIf
+                    }
+                }
+            }
+        };
+        self.compute_state();
+        dir
     }
-    pub fn push(&mut self, gate: Gate) {
-        self.state = self.state && gate.is_open();
+    /// Opens new chain (`#if`, `#ifdef`, `#ifndef`).
+    pub fn push(&mut self, gate: Gate) -> Directive {
         self.stack.push(GateState {
-            gate,
-            inverted: false,
+            open: false,
+            certain: false,
+            taken: false,
+            emitted: false,
+            finished: false,
         });
+        match self.branch(gate) {
+            Directive::AsIf => Directive::Keep,
+            dir => dir,
+        }
     }
-    pub fn pop(&mut self) -> Option<Gate> {
-        let gs_opt = self.stack.pop();
-        if let Some(gs) = gs_opt.as_ref() {
-            if !gs.gate.is_open() {
-                self.compute_state();
-            }
+    /// Switches to the next branch of the chain (`#elif`).
+    pub fn next(&mut self, gate: Gate) -> Result<Directive, ()> {
+        match self.stack.last() {
+            Some(last) if !last.finished => Ok(self.branch(gate)),
+            _ => Err(()),
         }
-        gs_opt.map(|x| x.gate)
+    }
+    /// Switches to the last branch of the chain (`#else`).
+    pub fn last(&mut self) -> Result<Directive, ()> {
+        let dir = self.next(Gate::Known(true))?;
+        self.stack.last_mut().unwrap().finished = true;
+        Ok(match dir {
+            Directive::AsElse => Directive::Keep,
+            dir => dir,
+        })
+    }
+    /// Closes the chain (`#endif`).
+    pub fn pop(&mut self) -> Result<Directive, ()> {
+        let gs = self.stack.pop().ok_or(())?;
+        self.compute_state();
+        Ok(if gs.emitted {
+            Directive::Keep
+        } else {
+            Directive::Skip
+        })
     }
     pub fn is_open(&self) -> bool {
         self.state
     }
-    pub fn invert_last(&mut self) -> Result<bool, ()> {
-        let last = self.stack.last_mut().ok_or(())?;
-        if !last.inverted {
-            last.inverted = true;
-            Ok(match &mut last.gate {
-                Gate::Known(_, value) => {
-                    *value = !*value;
-                    if *value {
-                        self.compute_state();
-                    } else {
-                        self.state = false;
-                    }
-                    true
-                }
-                Gate::Unknown => false,
-            })
-        } else {
-            Err(()) // already inverted
-        }
+    /// Code is known to be reached, i.e. all gates are open and resolved.
+    pub fn is_certain(&self) -> bool {
+        self.certain
     }
 }
@@ -0,0 +1,513 @@
+use super::parser::Defines;
+use std::{borrow::Cow, collections::HashMap};
+
+/// Maximum depth of nested macro expansion.
+const MAX_DEPTH: usize = 64;
+
+/// Preprocessor macro definition.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Macro {
+    /// Parameters of function-like macro, `None` for object-like one.
+    pub params: Option<Vec<String>>,
+    pub body: String,
+}
+
+impl Macro {
+    pub fn object(body: String) -> Self {
+        Self { params: None, body }
+    }
+
+    /// Parses parameter list (including parentheses) of function-like macro.
+    pub fn function(params: &str, body: String) -> Self {
+        let inner = params.trim_start_matches('(').trim_end_matches(')');
+        let params = inner
+            .split(',')
+            .map(|p| match p.trim() {
+                "..." => String::from("__VA_ARGS__"),
+                p => String::from(p),
+            })
+            .filter(|p| !p.is_empty())
+            .collect();
+        Self {
+            params: Some(params),
+            body,
+        }
+    }
+
+    fn is_variadic(&self) -> bool {
+        match &self.params {
+            Some(params) => params.last().map(|p| p == "__VA_ARGS__").unwrap_or(false),
+            None => false,
+        }
+    }
+}
+
+/// Known macros. `None` means that the name is known to be not defined.
+pub type Macros = HashMap<String, Option<Macro>>;
+
+pub fn from_defines(defines: &Defines) -> Macros {
+    defines
+        .iter()
+        .map(|(name, value)| (name.clone(), value.clone().map(Macro::object)))
+        .collect()
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Ident,
+    Number,
+    Literal,
+    Punct,
+    /// Whitespace or comment.
+    Space,
+}
+
+#[derive(Clone, Debug)]
+struct Token {
+    kind: Kind,
+    text: String,
+}
+
+impl Token {
+    fn new(kind: Kind, text: &str) -> Self {
+        Self {
+            kind,
+            text: String::from(text),
+        }
+    }
+    fn is_punct(&self, p: &str) -> bool {
+        self.kind == Kind::Punct && self.text == p
+    }
+}
+
+fn is_ident_start(c: char) -> bool {
+    c.is_ascii_alphabetic() || c == '_'
+}
+
+fn is_ident_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Length of string or character literal at the beginning of the text.
+fn literal_len(text: &str, quote: char) -> usize {
+    let mut escaped = false;
+    text[1..]
+        .find(|x| {
+            let end = !escaped && x == quote;
+            escaped = !escaped && x == '\\';
+            end
+        })
+        .map(|pos| pos + 2)
+        .unwrap_or(text.len())
+}
+
+/// Splits the text into tokens.
+///
+/// `in_comment` holds the state of multiline comment between lines.
+fn tokenize(text: &str, in_comment: &mut bool) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let c = rest.chars().next().unwrap();
+        let (kind, len) = if *in_comment {
+            let len = match rest.find("*/") {
+                Some(pos) => {
+                    *in_comment = false;
+                    pos + 2
+                }
+                None => rest.len(),
+            };
+            (Kind::Space, len)
+        } else if rest.starts_with("//") {
+            (Kind::Space, rest.find('\n').unwrap_or(rest.len()))
+        } else if rest.starts_with("/*") {
+            *in_comment = true;
+            (Kind::Space, 2)
+        } else if c.is_whitespace() {
+            let len = rest
+                .find(|x: char| !x.is_whitespace())
+                .unwrap_or(rest.len());
+            (Kind::Space, len)
+        } else if c == '"' || c == '\'' {
+            (Kind::Literal, literal_len(rest, c))
+        } else if is_ident_start(c) {
+            let len = rest.find(|x| !is_ident_char(x)).unwrap_or(rest.len());
+            (Kind::Ident, len)
+        } else if c.is_ascii_digit() {
+            let len = rest
+                .find(|x| !(is_ident_char(x) || x == '.'))
+                .unwrap_or(rest.len());
+            (Kind::Number, len)
+        } else if rest.starts_with("##") {
+            (Kind::Punct, 2)
+        } else {
+            (Kind::Punct, c.len_utf8())
+        };
+        match tokens.last_mut() {
+            // Merge comment parts with surrounding space.
+            Some(Token {
+                kind: Kind::Space,
+                text,
+            }) if kind == Kind::Space => text.push_str(&rest[..len]),
+            _ => tokens.push(Token::new(kind, &rest[..len])),
+        }
+        rest = &rest[len..];
+    }
+    tokens
+}
+
+/// Removes comments from the line, string and character literals are left untouched.
+pub fn strip_comments(line: &str) -> String {
+    tokenize(line, &mut false)
+        .into_iter()
+        .map(|t| match t.kind {
+            Kind::Space => Cow::Borrowed(" "),
+            _ => Cow::Owned(t.text),
+        })
+        .collect()
+}
+
+/// Result of looking for macro invocation arguments.
+enum Args {
+    /// Arguments and the position after closing parenthesis.
+    Found(Vec<Vec<Token>>, usize),
+    /// There is no argument list.
+    None,
+    /// Argument list is not closed.
+    Incomplete,
+}
+
+fn collect_args(tokens: &[Token], start: usize) -> Args {
+    let mut pos = start;
+    while pos < tokens.len() && tokens[pos].kind == Kind::Space {
+        pos += 1;
+    }
+    if pos >= tokens.len() || !tokens[pos].is_punct("(") {
+        return Args::None;
+    }
+    let mut args = vec![Vec::new()];
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate().skip(pos + 1) {
+        if token.kind == Kind::Punct {
+            match token.text.as_str() {
+                "(" => depth += 1,
+                ")" if depth == 0 => return Args::Found(args, i + 1),
+                ")" => depth -= 1,
+                "," if depth == 0 => {
+                    args.push(Vec::new());
+                    continue;
+                }
+                _ => (),
+            }
+        }
+        let token = match token.kind {
+            Kind::Space => Token::new(Kind::Space, " "),
+            _ => token.clone(),
+        };
+        args.last_mut().unwrap().push(token);
+    }
+    Args::Incomplete
+}
+
+fn trim(tokens: &[Token]) -> &[Token] {
+    let start = tokens
+        .iter()
+        .position(|t| t.kind != Kind::Space)
+        .unwrap_or(tokens.len());
+    let end = tokens
+        .iter()
+        .rposition(|t| t.kind != Kind::Space)
+        .map(|p| p + 1)
+        .unwrap_or(start);
+    &tokens[start..end]
+}
+
+fn stringize(tokens: &[Token]) -> Token {
+    let mut text = String::from("\"");
+    for token in trim(tokens) {
+        match token.kind {
+            Kind::Space => text.push(' '),
+            Kind::Literal => {
+                for c in token.text.chars() {
+                    if c == '"' || c == '\\' {
+                        text.push('\\');
+                    }
+                    text.push(c);
+                }
+            }
+            _ => text.push_str(&token.text),
+        }
+    }
+    text.push('"');
+    Token::new(Kind::Literal, &text)
+}
+
+/// Performs token pasting (`##` operator).
+fn paste(tokens: Vec<Token>) -> Vec<Token> {
+    let mut out: Vec<Token> = Vec::new();
+    let mut iter = tokens.into_iter().peekable();
+    while let Some(token) = iter.next() {
+        if !token.is_punct("##") {
+            out.push(token);
+            continue;
+        }
+        while out.last().map(|t| t.kind == Kind::Space).unwrap_or(false) {
+            out.pop();
+        }
+        while iter.peek().map(|t| t.kind == Kind::Space).unwrap_or(false) {
+            iter.next();
+        }
+        let mut text = out.pop().map(|t| t.text).unwrap_or_default();
+        if let Some(next) = iter.next() {
+            text.push_str(&next.text);
+        }
+        out.extend(tokenize(&text, &mut false));
+    }
+    out
+}
+
+struct Expander<'a> {
+    macros: &'a Macros,
+    /// Expand function-like macros and macros with empty body.
+    full: bool,
+    /// Names of macros that are being expanded.
+    stack: Vec<&'a str>,
+}
+
+impl<'a> Expander<'a> {
+    fn lookup(&self, name: &str) -> Option<(&'a str, &'a Macro)> {
+        match self.macros.get_key_value(name) {
+            Some((name, Some(m)))
+                if !self.stack.contains(&name.as_str())
+                    && self.stack.len() < MAX_DEPTH
+                    && (self.full || (m.params.is_none() && !m.body.is_empty())) =>
+            {
+                Some((name, m))
+            }
+            _ => None,
+        }
+    }
+
+    /// Substitutes arguments into the body of function-like macro.
+    fn substitute(&mut self, m: &Macro, mut args: Vec<Vec<Token>>) -> Option<Vec<Token>> {
+        let params = m.params.as_ref().unwrap();
+        if m.is_variadic() && args.len() >= params.len() {
+            let rest = args.split_off(params.len() - 1);
+            let mut va_args = Vec::new();
+            for (i, arg) in rest.into_iter().enumerate() {
+                if i > 0 {
+                    va_args.push(Token::new(Kind::Punct, ","));
+                }
+                va_args.extend(arg);
+            }
+            args.push(va_args);
+        }
+        if params.is_empty() && args.len() == 1 && trim(&args[0]).is_empty() {
+            args.clear();
+        }
+        if args.len() != params.len() {
+            return None;
+        }
+
+        let body = tokenize(&m.body, &mut false);
+        let arg = |token: &Token| {
+            if token.kind == Kind::Ident {
+                params.iter().position(|p| *p == token.text)
+            } else {
+                None
+            }
+        };
+        let mut out = Vec::new();
+        let mut prev: Option<&Token> = None;
+        let mut iter = body.iter().enumerate();
+        while let Some((i, token)) = iter.next() {
+            let next = body[(i + 1)..].iter().find(|t| t.kind != Kind::Space);
+            if token.is_punct("#") {
+                if let Some(k) = next.and_then(arg) {
+                    out.push(stringize(&args[k]));
+                    // Skip the parameter name.
+                    for (_, t) in iter.by_ref() {
+                        if t.kind != Kind::Space {
+                            break;
+                        }
+                    }
+                    prev = next;
+                    continue;
+                }
+            }
+            match arg(token) {
+                Some(k) => {
+                    let pasted = prev.map(|t| t.is_punct("##")).unwrap_or(false)
+                        || next.map(|t| t.is_punct("##")).unwrap_or(false);
+                    if pasted {
+                        out.extend(trim(&args[k]).iter().cloned());
+                    } else {
+                        self.expand(trim(&args[k]), &mut out);
+                    }
+                }
+                None => out.push(token.clone()),
+            }
+            if token.kind != Kind::Space {
+                prev = Some(token);
+            }
+        }
+        Some(paste(out))
+    }
+
+    /// Expands macros in tokens.
+    fn expand(&mut self, tokens: &[Token], out: &mut Vec<Token>) -> bool {
+        let mut changed = false;
+        let mut pos = 0;
+        while pos < tokens.len() {
+            match self.expand_next(tokens, pos, out) {
+                Some(end) => {
+                    pos = end;
+                    changed = true;
+                }
+                None => {
+                    out.push(tokens[pos].clone());
+                    pos += 1;
+                }
+            }
+        }
+        changed
+    }
+
+    /// Expands macro invocation starting at `pos` and returns the position after it,
+    /// or `None` if there is no invocation.
+    fn expand_next(&mut self, tokens: &[Token], pos: usize, out: &mut Vec<Token>) -> Option<usize> {
+        let token = &tokens[pos];
+        let (name, m) = match token.kind {
+            Kind::Ident => self.lookup(&token.text)?,
+            _ => return None,
+        };
+        let (body, end) = match m.params {
+            None => (paste(tokenize(&m.body, &mut false)), pos + 1),
+            Some(_) => match collect_args(tokens, pos + 1) {
+                Args::Found(args, end) => (self.substitute(m, args)?, end),
+                _ => return None,
+            },
+        };
+        let start = out.len();
+        self.stack.push(name);
+        self.expand(&body, out);
+        self.stack.pop();
+        Some(self.rescan_tail(name, tokens, end, start, out))
+    }
+
+    /// Rescans the expansion of `name` written to `out[start..]` together with the tokens following it.
+    ///
+    /// If the expansion ends with the name of function-like macro and its arguments follow the invocation
+    /// in `tokens` from `end`, then the macro is expanded too. Returns the position after the consumed tokens.
+    fn rescan_tail(
+        &mut self,
+        name: &str,
+        tokens: &[Token],
+        end: usize,
+        start: usize,
+        out: &mut Vec<Token>,
+    ) -> usize {
+        let last = match out[start..].iter().rposition(|t| t.kind != Kind::Space) {
+            Some(i) => start + i,
+            None => return end,
+        };
+        let tail = &out[last];
+        // The macro itself is not expanded again like in C.
+        if tail.kind != Kind::Ident || tail.text == name {
+            return end;
+        }
+        match self.lookup(&tail.text) {
+            Some((_, m)) if m.params.is_some() => (),
+            _ => return end,
+        }
+        if !matches!(collect_args(tokens, end), Args::Found(..)) {
+            return end;
+        }
+        let mut rest = vec![tail.clone()];
+        rest.extend_from_slice(&tokens[end..]);
+        let mut expanded = Vec::new();
+        match self.expand_next(&rest, 0, &mut expanded) {
+            Some(rest_end) => {
+                out.truncate(last);
+                out.extend(expanded);
+                end + rest_end - 1
+            }
+            None => end,
+        }
+    }
+
+    /// Checks whether the tokens end inside of macro invocation.
+    fn is_incomplete(&self, tokens: &[Token]) -> bool {
+        tokens.iter().enumerate().any(|(i, token)| {
+            token.kind == Kind::Ident
+                && self
+                    .lookup(&token.text)
+                    .map(|(_, m)| m.params.is_some())
+                    .unwrap_or(false)
+                && matches!(collect_args(tokens, i + 1), Args::Incomplete)
+        })
+    }
+}
+
+/// Expands macros in the line of code.
+///
+/// If `full` is `false` then only object-like macros with non-empty body are expanded.
+/// Line breaks in expanded text are replaced with spaces.
+///
+/// Returns the resulting line and the chunks of it as `(start, original_start, copied)`,
+/// chunks are empty if the line is not changed.
+pub fn expand_line<'a>(
+    line: &'a str,
+    macros: &Macros,
+    full: bool,
+    in_comment: &mut bool,
+) -> (Cow<'a, str>, Vec<(usize, usize, bool)>) {
+    let tokens = tokenize(line, in_comment);
+    let mut expander = Expander {
+        macros,
+        full,
+        stack: Vec::new(),
+    };
+    let mut out = Vec::new();
+    let mut chunks: Vec<(usize, usize, bool)> = Vec::new();
+    let (mut pos, mut len, mut orig_len) = (0, 0, 0);
+    let mut changed = false;
+    while pos < tokens.len() {
+        let start = out.len();
+        let end = match expander.expand_next(&tokens, pos, &mut out) {
+            Some(end) => {
+                chunks.push((len, orig_len, false));
+                changed = true;
+                end
+            }
+            None => {
+                match chunks.last() {
+                    Some((s, os, true)) if len - s == orig_len - os => (),
+                    _ => chunks.push((len, orig_len, true)),
+                }
+                out.push(tokens[pos].clone());
+                pos + 1
+            }
+        };
+        orig_len += tokens[pos..end].iter().map(|t| t.text.len()).sum::<usize>();
+        len += out[start..].iter().map(|t| t.text.len()).sum::<usize>();
+        pos = end;
+    }
+    if changed {
+        let text = out.into_iter().map(|t| t.text).collect::<String>();
+        (Cow::Owned(text.replace('\n', " ")), chunks)
+    } else {
+        (Cow::Borrowed(line), Vec::new())
+    }
+}
+
+/// Checks whether the line ends inside of function-like macro invocation.
+pub fn is_incomplete(line: &str, macros: &Macros, in_comment: bool) -> bool {
+    let tokens = tokenize(line, &mut { in_comment });
+    let expander = Expander {
+        macros,
+        full: true,
+        stack: Vec::new(),
+    };
+    expander.is_incomplete(&tokens)
+}
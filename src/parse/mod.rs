@@ -1,6 +1,10 @@
 mod context;
+mod expr;
 mod file_context;
 mod gate;
+mod macros;
 mod parser;
 
-pub use parser::{Flags, Parser, ParserBuilder};
+#[allow(deprecated)]
+pub use parser::Flags;
+pub use parser::{Defines, Parser, ParserBuilder, Session};
@@ -1,9 +1,11 @@
-use super::context::Context;
-use crate::{node::Node, source::Source};
+use super::{
+    context::Context,
+    macros::{self, Macros},
+};
+use crate::{error::Error, node::Node, source::Source};
 use std::{
-    cell::RefCell,
     collections::hash_map::HashMap,
-    io,
+    mem,
     path::{Path, PathBuf},
 };
 
@@ -17,18 +19,42 @@ impl FileCacheEntry {
 }
 pub type FileCache = HashMap<PathBuf, FileCacheEntry>;
 
+/// Known preprocessor definitions.
+///
+/// `Some(value)` means that the name is defined with the value (possibly empty),
+/// `None` means that the name is known to be not defined.
+pub type Defines = HashMap<String, Option<String>>;
+
+#[deprecated(note = "use `Defines` instead")]
 pub type Flags = HashMap<String, bool>;
 
+#[derive(Clone)]
+pub struct Options {
+    pub substitute: bool,
+    pub expand: bool,
+    pub keep_defines: bool,
+}
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            substitute: false,
+            expand: false,
+            keep_defines: true,
+        }
+    }
+}
+
 pub struct Parser {
     source: Box<dyn Source>,
-    flags: Flags,
-    file_cache: RefCell<FileCache>,
+    defines: Defines,
+    options: Options,
 }
 
 #[derive(Default)]
 pub struct ParserBuilder {
     sources: Vec<Box<dyn Source>>,
-    flags: Flags,
+    defines: Defines,
+    options: Options,
 }
 
 impl ParserBuilder {
@@ -37,22 +63,67 @@ impl ParserBuilder {
         self
     }
 
+    /// Marks the name as defined with empty value or as not defined.
     pub fn add_flag(mut self, name: String, value: bool) -> Self {
-        self.flags.insert(name, value);
+        self.defines
+            .insert(name, if value { Some(String::new()) } else { None });
+        self
+    }
+
+    /// Defines the name with the value.
+    pub fn add_define<N: Into<String>, V: Into<String>>(mut self, name: N, value: V) -> Self {
+        self.defines.insert(name.into(), Some(value.into()));
+        self
+    }
+
+    /// Enables replacement of defined names with their values in the code.
+    ///
+    /// Only names with non-empty values are replaced.
+    pub fn substitute(mut self, enable: bool) -> Self {
+        self.options.substitute = enable;
+        self
+    }
+
+    /// Enables expansion of all known macros in the code, including function-like ones.
+    ///
+    /// Line numbers are preserved: if macro invocation spans multiple lines,
+    /// the expanded code is placed at the first of them and the rest become empty.
+    pub fn expand_macros(mut self, enable: bool) -> Self {
+        self.options.expand = enable;
+        self
+    }
+
+    /// Whether to keep `#define` and `#undef` directives in the code (enabled by default).
+    ///
+    /// Directives inside unresolved gates are always kept.
+    pub fn keep_defines(mut self, enable: bool) -> Self {
+        self.options.keep_defines = enable;
         self
     }
 
     pub fn build(self) -> Parser {
-        Parser::new(Box::new(self.sources), self.flags)
+        let mut parser = Parser::with_defines(Box::new(self.sources), self.defines);
+        parser.options = self.options;
+        parser
     }
 }
 
 impl Parser {
-    pub fn new(source: Box<dyn Source>, flags: Flags) -> Self {
+    /// Creates parser with the names marked as defined (with empty value) or as not defined.
+    pub fn new(source: Box<dyn Source>, flags: HashMap<String, bool>) -> Self {
+        let defines = flags
+            .into_iter()
+            .map(|(name, value)| (name, if value { Some(String::new()) } else { None }))
+            .collect();
+        Self::with_defines(source, defines)
+    }
+
+    /// Creates parser with the known definitions.
+    pub fn with_defines(source: Box<dyn Source>, defines: Defines) -> Self {
         Self {
             source,
-            flags,
-            file_cache: RefCell::new(HashMap::new()),
+            defines,
+            options: Options::default(),
         }
     }
 
@@ -60,19 +131,67 @@ impl Parser {
         ParserBuilder::default()
     }
 
+    pub(crate) fn source(&self) -> &dyn Source {
+        self.source.as_ref()
+    }
+
     /// Reads and parses source files and resolves dependencies.
     ///
     /// Returns node tree that could be collected into resulting code string and index.
-    pub fn parse(&self, main: &Path) -> io::Result<Node> {
-        let mut file_cache = self.file_cache.borrow_mut();
-        let mut context = Context::new(self.source.as_ref(), &self.flags, &mut file_cache);
-        context.build_tree(main, None).and_then(|root| {
-            root.ok_or_else(|| {
-                io::Error::new(
-                    io::ErrorKind::NotFound,
-                    format!("Root file {:?} not found", main),
-                )
-            })
-        })
+    ///
+    /// Each call is independent: files with `#pragma once` included by previous calls
+    /// are included again. Use `session` to parse several files as a single unit.
+    pub fn parse(&self, main: &Path) -> Result<Node, Error> {
+        self.parse_with(
+            main,
+            &mut HashMap::new(),
+            macros::from_defines(&self.defines),
+        )
+        .0
+    }
+
+    /// Creates session that shares state between parsed files.
+    pub fn session(&self) -> Session<'_> {
+        Session {
+            parser: self,
+            file_cache: HashMap::new(),
+            macros: macros::from_defines(&self.defines),
+        }
+    }
+
+    fn parse_with(
+        &self,
+        main: &Path,
+        file_cache: &mut FileCache,
+        macros: Macros,
+    ) -> (Result<Node, Error>, Macros) {
+        let mut context = Context::new(self.source.as_ref(), macros, &self.options, file_cache);
+        // Root is skipped if it has `#pragma once` and was already included in the session.
+        let res = context
+            .build_tree(main, None, true)
+            .map(|(path, root)| root.unwrap_or_else(|| Node::new(&path)));
+        (res, context.into_macros())
+    }
+}
+
+/// Parses several files as a single unit.
+///
+/// Files with `#pragma once` are included only once across all parsed files,
+/// and macros defined in the code of previous files are known in the next ones.
+pub struct Session<'a> {
+    parser: &'a Parser,
+    file_cache: FileCache,
+    macros: Macros,
+}
+
+impl<'a> Session<'a> {
+    /// Parses the file continuing the state of the session, see `Parser::parse`.
+    ///
+    /// If the file has `#pragma once` and was already included, the returned node is empty.
+    pub fn parse(&mut self, main: &Path) -> Result<Node, Error> {
+        let macros = mem::take(&mut self.macros);
+        let (res, macros) = self.parser.parse_with(main, &mut self.file_cache, macros);
+        self.macros = macros;
+        res
     }
 }
@@ -0,0 +1,95 @@
+use std::{
+    fs,
+    io::{self, Cursor, Read, Seek},
+    path::{Path, PathBuf},
+    str,
+};
+
+use super::{Mem, Source};
+
+/// Source for retrieving files from zip, tar or gzipped tar archive.
+///
+/// Archive is unpacked into memory at once, relative includes are resolved against its directory structure
+/// like in `Mem`. Entries with names that are not valid UTF-8 are reported as `InvalidData` error,
+/// and entries with content that is not valid UTF-8 text are skipped.
+/// If there are several entries with the same name, the last one is used, like when unpacking tar.
+#[derive(Default)]
+pub struct Archive {
+    files: Mem,
+}
+
+impl Archive {
+    /// Reads the archive file, the format is detected by its content.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+
+    /// Reads the archive from the memory, the format is detected by its content.
+    pub fn from_bytes(data: &[u8]) -> io::Result<Self> {
+        if data.starts_with(b"PK") {
+            Self::from_zip(Cursor::new(data))
+        } else if data.starts_with(&[0x1f, 0x8b]) {
+            Self::from_tar_gz(data)
+        } else {
+            Self::from_tar(data)
+        }
+    }
+
+    pub fn from_zip<R: Read + Seek>(reader: R) -> io::Result<Self> {
+        let mut zip = zip::ZipArchive::new(reader)?;
+        let mut archive = Self::default();
+        for i in 0..zip.len() {
+            let mut file = zip.by_index(i)?;
+            if file.is_file() {
+                let name = PathBuf::from(entry_name(file.name_raw())?);
+                archive.add_entry(&name, &mut file)?;
+            }
+        }
+        Ok(archive)
+    }
+
+    pub fn from_tar<R: Read>(reader: R) -> io::Result<Self> {
+        let mut tar = tar::Archive::new(reader);
+        let mut archive = Self::default();
+        for entry in tar.entries()? {
+            let mut entry = entry?;
+            if entry.header().entry_type().is_file() {
+                let name = PathBuf::from(entry_name(&entry.path_bytes())?);
+                archive.add_entry(&name, &mut entry)?;
+            }
+        }
+        Ok(archive)
+    }
+
+    pub fn from_tar_gz<R: Read>(reader: R) -> io::Result<Self> {
+        Self::from_tar(flate2::read::GzDecoder::new(reader))
+    }
+
+    fn add_entry<R: Read>(&mut self, name: &Path, reader: &mut R) -> io::Result<()> {
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        match String::from_utf8(data) {
+            Ok(text) => self.files.replace_file(name, text),
+            Err(_) => self.files.remove_file(name),
+        }
+        Ok(())
+    }
+}
+
+fn entry_name(name: &[u8]) -> io::Result<&str> {
+    str::from_utf8(name).map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "archive entry name is not valid UTF-8: {:?}",
+                String::from_utf8_lossy(name)
+            ),
+        )
+    })
+}
+
+impl Source for Archive {
+    fn read(&self, path: &Path, dir: Option<&Path>) -> io::Result<(PathBuf, String)> {
+        self.files.read(path, dir)
+    }
+}
@@ -2,36 +2,64 @@ use super::Source;
 use std::{
     io,
     path::{Path, PathBuf},
-    rc::Rc,
+    sync::Arc,
 };
 
+/// Returns the result of the first source that has the file.
+fn read_first<S: Source, F: Fn(&S) -> io::Result<(PathBuf, String)>>(
+    sources: &[S],
+    path: &Path,
+    dir: Option<&Path>,
+    read: F,
+) -> io::Result<(PathBuf, String)> {
+    let mut res = None;
+    for source in sources.iter() {
+        match read(source) {
+            Ok(x) => {
+                res = Some(Ok(x));
+                break;
+            }
+            Err(e) => match e.kind() {
+                io::ErrorKind::NotFound => continue,
+                _ => {
+                    res = Some(Err(e));
+                    break;
+                }
+            },
+        }
+    }
+
+    match res {
+        Some(x) => x,
+        None => Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("path: {:?}, dir: {:?}", path, dir),
+        )),
+    }
+}
+
 /// Vector of sources is also source.
 impl<S: Source> Source for Vec<S> {
     fn read(&self, path: &Path, dir: Option<&Path>) -> io::Result<(PathBuf, String)> {
-        let mut res = None;
+        read_first(self, path, dir, |source| source.read(path, dir))
+    }
+
+    fn read_main(&self, path: &Path) -> io::Result<(PathBuf, String)> {
+        read_first(self, path, None, |source| source.read_main(path))
+    }
+
+    fn invalidate(&self, path: &Path) -> bool {
+        let mut found = false;
         for source in self.iter() {
-            match source.read(path, dir) {
-                Ok(x) => {
-                    res = Some(Ok(x));
-                    break;
-                }
-                Err(e) => match e.kind() {
-                    io::ErrorKind::NotFound => continue,
-                    _ => {
-                        res = Some(Err(e));
-                        break;
-                    }
-                },
-            }
+            found |= source.invalidate(path);
         }
+        found
+    }
 
-        match res {
-            Some(x) => x,
-            None => Err(io::Error::new(
-                io::ErrorKind::NotFound,
-                format!("path: {:?}, dir: {:?}", path, dir),
-            )),
-        }
+    fn candidates(&self, path: &Path, dir: Option<&Path>) -> Vec<PathBuf> {
+        self.iter()
+            .flat_map(|source| source.candidates(path, dir))
+            .collect()
     }
 }
 
@@ -47,6 +75,18 @@ impl<'a, S: Source> Source for &'a S {
     fn read(&self, path: &Path, dir: Option<&Path>) -> io::Result<(PathBuf, String)> {
         (*self).read(path, dir)
     }
+
+    fn read_main(&self, path: &Path) -> io::Result<(PathBuf, String)> {
+        (*self).read_main(path)
+    }
+
+    fn invalidate(&self, path: &Path) -> bool {
+        (*self).invalidate(path)
+    }
+
+    fn candidates(&self, path: &Path, dir: Option<&Path>) -> Vec<PathBuf> {
+        (*self).candidates(path, dir)
+    }
 }
 
 /// Source Box is also source.
@@ -54,11 +94,35 @@ impl Source for Box<dyn Source> {
     fn read(&self, path: &Path, dir: Option<&Path>) -> io::Result<(PathBuf, String)> {
         self.as_ref().read(path, dir)
     }
+
+    fn read_main(&self, path: &Path) -> io::Result<(PathBuf, String)> {
+        self.as_ref().read_main(path)
+    }
+
+    fn invalidate(&self, path: &Path) -> bool {
+        self.as_ref().invalidate(path)
+    }
+
+    fn candidates(&self, path: &Path, dir: Option<&Path>) -> Vec<PathBuf> {
+        self.as_ref().candidates(path, dir)
+    }
 }
 
-/// Source Rc is also source.
-impl<S: Source> Source for Rc<S> {
+/// Source Arc is also source.
+impl<S: Source> Source for Arc<S> {
     fn read(&self, path: &Path, dir: Option<&Path>) -> io::Result<(PathBuf, String)> {
         self.as_ref().read(path, dir)
     }
+
+    fn read_main(&self, path: &Path) -> io::Result<(PathBuf, String)> {
+        self.as_ref().read_main(path)
+    }
+
+    fn invalidate(&self, path: &Path) -> bool {
+        self.as_ref().invalidate(path)
+    }
+
+    fn candidates(&self, path: &Path, dir: Option<&Path>) -> Vec<PathBuf> {
+        self.as_ref().candidates(path, dir)
+    }
 }
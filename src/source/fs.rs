@@ -1,23 +1,72 @@
 use std::{
-    cell::Cell,
-    collections::hash_map::{Entry, HashMap},
-    fs, io,
+    collections::HashMap,
+    env, error, fmt, fs, io,
     path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
 };
 
-use super::Source;
+use super::{normalize, Source};
+
+/// Modification time and size of the file at the moment it was read.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Stamp {
+    modified: Option<SystemTime>,
+    len: u64,
+}
+
+impl Stamp {
+    pub(crate) fn of(path: &Path) -> Option<Self> {
+        fs::metadata(path).ok().map(|meta| Stamp {
+            modified: meta.modified().ok(),
+            len: meta.len(),
+        })
+    }
+}
+
+/// Payload of `PermissionDenied` error returned by sandboxed source.
+#[derive(Debug)]
+pub(crate) struct OutsideRoots;
+
+impl fmt::Display for OutsideRoots {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "path is outside of include directories")
+    }
+}
+
+impl error::Error for OutsideRoots {}
+
+fn forbidden() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, OutsideRoots)
+}
+
+struct CacheEntry {
+    text: String,
+    stamp: Option<Stamp>,
+    /// Path the file was read from, it may differ from the cache key.
+    path: PathBuf,
+}
 
 /// Source for reading files from filesystem.
+///
+/// Files are cached after the first read, use `invalidate`, `clear` or `check_modified` option
+/// to pick up changes made on disk.
 pub struct Fs {
     inc_dirs: Vec<PathBuf>,
-    cache: Cell<Option<HashMap<PathBuf, String>>>,
+    canonicalize: bool,
+    check_modified: bool,
+    sandbox: bool,
+    cache: Mutex<HashMap<PathBuf, CacheEntry>>,
 }
 
 impl Default for Fs {
     fn default() -> Self {
         Fs {
             inc_dirs: Vec::new(),
-            cache: Cell::new(Some(HashMap::new())),
+            canonicalize: false,
+            check_modified: false,
+            sandbox: false,
+            cache: Mutex::new(HashMap::new()),
         }
     }
 }
@@ -39,6 +88,130 @@ impl Fs {
         Ok(())
     }
 
+    /// Resolve symbolic links in paths of found files instead of lexical normalization,
+    /// so a file reached through different links is recognized as the same file.
+    pub fn set_canonicalize(&mut self, enable: bool) {
+        self.canonicalize = enable;
+    }
+
+    /// Compare modification time and size of the cached file with the actual ones on every read
+    /// and reread the file if they differ.
+    pub fn set_check_modified(&mut self, enable: bool) {
+        self.check_modified = enable;
+    }
+
+    /// Refuse to read files outside of the include directories, including the main one.
+    ///
+    /// Absolute includes are rejected, only the path of the main file may be absolute.
+    /// Paths outside of the include directories are refused before accessing them,
+    /// and found files are checked again after resolving symbolic links.
+    /// The resolved path is the one that is read, so found files are named by it.
+    /// Refused files are reported as `Error::Forbidden`.
+    pub fn set_sandbox(&mut self, enable: bool) {
+        self.sandbox = enable;
+    }
+
+    /// Returns the canonical path if it is inside of some include directory.
+    fn check_sandbox(&self, path: &Path) -> io::Result<PathBuf> {
+        let path = fs::canonicalize(path)?;
+        for dir in self.inc_dirs.iter() {
+            if path.starts_with(fs::canonicalize(dir)?) {
+                return Ok(path);
+            }
+        }
+        Err(forbidden())
+    }
+
+    /// Finds the file like `find_file_raw`, but candidates outside of include directories
+    /// are skipped without accessing them, so that nothing is revealed about files there.
+    fn find_file_sandboxed(
+        &self,
+        dir: Option<&Path>,
+        name: &Path,
+        main: bool,
+    ) -> io::Result<PathBuf> {
+        let candidates = if name.is_absolute() {
+            if !main {
+                return Err(forbidden());
+            }
+            vec![name.to_path_buf()]
+        } else {
+            dir.into_iter()
+                .chain(self.inc_dirs.iter().map(|dir| dir.as_path()))
+                .map(|dir| dir.join(name))
+                .collect()
+        };
+        let cwd = env::current_dir()?;
+        let roots = self
+            .inc_dirs
+            .iter()
+            .map(|dir| normalize(&cwd.join(dir)))
+            .collect::<Vec<_>>();
+        let mut inside = false;
+        for path in candidates {
+            let lexical = normalize(&cwd.join(&path));
+            if !roots.iter().any(|root| lexical.starts_with(root)) {
+                continue;
+            }
+            inside = true;
+            match self.check_file(&path) {
+                Ok(()) => return Ok(path),
+                Err(e) => match e.kind() {
+                    io::ErrorKind::NotFound => (),
+                    _ => return Err(e),
+                },
+            }
+        }
+        if inside {
+            Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                name.to_string_lossy(),
+            ))
+        } else {
+            Err(forbidden())
+        }
+    }
+
+    fn cache_key(&self, path: &Path) -> PathBuf {
+        if self.canonicalize || self.sandbox {
+            if let Ok(path) = fs::canonicalize(path) {
+                return path;
+            }
+        }
+        normalize(path)
+    }
+
+    /// Removes the file from the cache, so it will be read again next time.
+    ///
+    /// Returns `true` if the file was cached.
+    pub fn invalidate(&self, path: &Path) -> bool {
+        let key = self.cache_key(path);
+        self.cache.lock().unwrap().remove(&key).is_some()
+    }
+
+    /// Removes all files from the cache.
+    pub fn clear(&self) {
+        self.cache.lock().unwrap().clear();
+    }
+
+    /// Returns cached files that were modified or removed since they were read.
+    pub fn changed(&self) -> Vec<PathBuf> {
+        let stamps = self
+            .cache
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, entry)| (key.clone(), entry.path.clone(), entry.stamp))
+            .collect::<Vec<_>>();
+        let mut files = stamps
+            .into_iter()
+            .filter(|(_, path, stamp)| Stamp::of(path) != *stamp)
+            .map(|(key, _, _)| key)
+            .collect::<Vec<_>>();
+        files.sort();
+        files
+    }
+
     fn check_dir(&self, dir: &Path) -> io::Result<()> {
         let meta = fs::metadata(dir)?;
         if !meta.is_dir() {
@@ -52,10 +225,7 @@ impl Fs {
     }
 
     fn check_file(&self, path: &Path) -> io::Result<()> {
-        let map = self.cache.take().unwrap();
-        let contains = map.contains_key(path);
-        self.cache.set(Some(map));
-        if contains {
+        if !self.check_modified && self.cache.lock().unwrap().contains_key(path) {
             return Ok(());
         }
 
@@ -85,7 +255,42 @@ impl Fs {
         }
     }
 
-    fn find_file(&self, dir: Option<&Path>, name: &Path) -> io::Result<PathBuf> {
+    /// Returns the path to read the file from and the name of the file.
+    ///
+    /// Unless paths are canonicalized, the name is normalized lexically and may point to another file
+    /// if there are symbolic links followed by `..`, so the file is read from the found path.
+    fn find_file(
+        &self,
+        dir: Option<&Path>,
+        name: &Path,
+        main: bool,
+    ) -> io::Result<(PathBuf, PathBuf)> {
+        let path = if self.sandbox {
+            let path = self.find_file_sandboxed(dir, name, main)?;
+            // Read exactly the checked path, so that a link replaced in between is not followed.
+            self.check_sandbox(&path)?
+        } else if self.canonicalize {
+            let path = self.find_file_raw(dir, name)?;
+            fs::canonicalize(path)?
+        } else {
+            let path = self.find_file_raw(dir, name)?;
+            let name = normalize(&path);
+            return Ok((path, name));
+        };
+        Ok((path.clone(), path))
+    }
+
+    fn candidates(&self, dir: Option<&Path>, name: &Path) -> Vec<PathBuf> {
+        if name.is_absolute() {
+            return vec![name.to_path_buf()];
+        }
+        dir.into_iter()
+            .chain(self.inc_dirs.iter().map(|dir| dir.as_path()))
+            .map(|dir| normalize(&dir.join(name)))
+            .collect()
+    }
+
+    fn find_file_raw(&self, dir: Option<&Path>, name: &Path) -> io::Result<PathBuf> {
         if name.is_absolute() {
             return Ok(name.to_path_buf());
         }
@@ -107,6 +312,34 @@ impl Fs {
             name.to_string_lossy(),
         ))
     }
+
+    /// Reads the found file from `path` or takes it from the cache by `name`.
+    fn read_found(&self, path: PathBuf, name: PathBuf) -> io::Result<(PathBuf, String)> {
+        // The lock is held only to access the cache, not during reading,
+        // so that parsers in other threads are not blocked.
+        let cached = self
+            .cache
+            .lock()
+            .unwrap()
+            .get(&name)
+            .map(|entry| (entry.text.clone(), entry.stamp));
+        let text = match cached {
+            Some((text, _)) if !self.check_modified => text,
+            Some((text, stamp)) if Stamp::of(&path) == stamp => text,
+            _ => {
+                let stamp = Stamp::of(&path);
+                let text = fs::read_to_string(&path)?;
+                let entry = CacheEntry {
+                    text: text.clone(),
+                    stamp,
+                    path,
+                };
+                self.cache.lock().unwrap().insert(name.clone(), entry);
+                text
+            }
+        };
+        Ok((name, text))
+    }
 }
 
 pub struct FsBuilder {
@@ -118,6 +351,21 @@ impl FsBuilder {
         self.source.include_dir(dir.as_ref()).map(|()| self)
     }
 
+    pub fn canonicalize(mut self, enable: bool) -> Self {
+        self.source.set_canonicalize(enable);
+        self
+    }
+
+    pub fn check_modified(mut self, enable: bool) -> Self {
+        self.source.set_check_modified(enable);
+        self
+    }
+
+    pub fn sandbox(mut self, enable: bool) -> Self {
+        self.source.set_sandbox(enable);
+        self
+    }
+
     pub fn build(self) -> Fs {
         self.source
     }
@@ -125,20 +373,20 @@ impl FsBuilder {
 
 impl Source for Fs {
     fn read(&self, path: &Path, dir: Option<&Path>) -> io::Result<(PathBuf, String)> {
-        self.find_file(dir, path).and_then(|path| {
-            let mut map = self.cache.take().unwrap();
-
-            let res = match map.entry(path.clone()) {
-                Entry::Occupied(v) => Ok(v.get().clone()),
-                Entry::Vacant(v) => fs::read_to_string(&path).map(|data| {
-                    v.insert(data.clone());
-                    data
-                }),
-            }
-            .map(|data| (path, data));
+        self.find_file(dir, path, false)
+            .and_then(|(path, name)| self.read_found(path, name))
+    }
 
-            self.cache.set(Some(map));
-            res
-        })
+    fn read_main(&self, path: &Path) -> io::Result<(PathBuf, String)> {
+        self.find_file(None, path, true)
+            .and_then(|(path, name)| self.read_found(path, name))
+    }
+
+    fn invalidate(&self, path: &Path) -> bool {
+        Fs::invalidate(self, path)
+    }
+
+    fn candidates(&self, path: &Path, dir: Option<&Path>) -> Vec<PathBuf> {
+        Fs::candidates(self, dir, path)
     }
 }
@@ -4,7 +4,7 @@ use std::{
     path::{Path, PathBuf},
 };
 
-use super::Source;
+use super::{normalize, Source};
 
 /// Source for retrieving files from memory.
 #[derive(Default)]
@@ -24,7 +24,7 @@ impl Mem {
     }
 
     pub fn add_file<P: AsRef<Path>>(&mut self, name: &P, data: String) -> io::Result<()> {
-        match self.files.entry(name.as_ref().to_path_buf()) {
+        match self.files.entry(normalize(name.as_ref())) {
             Entry::Occupied(_) => Err(io::ErrorKind::AlreadyExists.into()),
             Entry::Vacant(v) => {
                 v.insert(data);
@@ -33,8 +33,20 @@ impl Mem {
         }
     }
 
-    fn read_file<P: AsRef<Path>>(&self, path: &P) -> Option<String> {
-        self.files.get(path.as_ref()).cloned()
+    /// Adds the file replacing the existing one with the same name.
+    #[cfg(feature = "archive")]
+    pub(crate) fn replace_file(&mut self, name: &Path, data: String) {
+        self.files.insert(normalize(name), data);
+    }
+
+    #[cfg(feature = "archive")]
+    pub(crate) fn remove_file(&mut self, name: &Path) {
+        self.files.remove(&normalize(name));
+    }
+
+    fn read_file(&self, path: &Path) -> Option<(PathBuf, String)> {
+        let path = normalize(path);
+        self.files.get(&path).map(|data| (path, data.clone()))
     }
 }
 
@@ -54,20 +66,13 @@ impl MemBuilder {
 
 impl Source for Mem {
     fn read(&self, path: &Path, dir: Option<&Path>) -> io::Result<(PathBuf, String)> {
-        dir.and_then(|dir| {
-            let path = dir.join(path);
-            self.read_file(&path).map(|data| (path, data))
-        })
-        .or_else(|| {
-            self.files
-                .get(path)
-                .map(|data| (path.to_path_buf(), data.clone()))
-        })
-        .ok_or_else(|| {
-            io::Error::new(
-                io::ErrorKind::NotFound,
-                format!("path: {:?}, dir: {:?}", path, dir),
-            )
-        })
+        dir.and_then(|dir| self.read_file(&dir.join(path)))
+            .or_else(|| self.read_file(path))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("path: {:?}, dir: {:?}", path, dir),
+                )
+            })
     }
 }
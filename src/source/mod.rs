@@ -1,14 +1,18 @@
+#[cfg(feature = "archive")]
+pub mod archive;
 pub mod container;
 pub mod fs;
 pub mod mem;
 
 use std::{
     io,
-    path::{Path, PathBuf},
+    path::{Component, Path, PathBuf},
 };
 
 /// Something that may provide file content by its name.
-pub trait Source {
+///
+/// Sources are shared between threads, so they should use synchronized caches if any.
+pub trait Source: Send + Sync {
     /// Performs file loading
     ///
     /// Arguments:
@@ -19,7 +23,54 @@ pub trait Source {
     /// + Absolute path to file
     /// + File content
     fn read(&self, path: &Path, dir: Option<&Path>) -> io::Result<(PathBuf, String)>;
+
+    /// Loads the main file passed to the parser, by default it is the same as `read` without `dir`.
+    ///
+    /// Sources may treat it differently from included files, e.g. sandboxed `Fs` allows its path to be absolute.
+    fn read_main(&self, path: &Path) -> io::Result<(PathBuf, String)> {
+        self.read(path, None)
+    }
+
+    /// Drops cached content of the file, so it will be read again.
+    ///
+    /// `path` is the one returned by `read`. Returns `true` if the file was cached.
+    fn invalidate(&self, _path: &Path) -> bool {
+        false
+    }
+
+    /// Paths on the filesystem where the file is looked for, in the same order.
+    ///
+    /// It is used to notice creation of the missing file, sources not backed by filesystem return nothing.
+    fn candidates(&self, _path: &Path, _dir: Option<&Path>) -> Vec<PathBuf> {
+        Vec::new()
+    }
+}
+
+/// Lexically normalizes the path: removes `.` components and resolves `..` against the preceding ones.
+///
+/// Symbolic links are not taken into account, e.g. `link/../a.h` becomes `a.h` even if `link` is a symlink.
+pub fn normalize(path: &Path) -> PathBuf {
+    let mut out = PathBuf::new();
+    for comp in path.components() {
+        match comp {
+            Component::CurDir => (),
+            Component::ParentDir => match out.components().next_back() {
+                Some(Component::Normal(_)) => {
+                    out.pop();
+                }
+                Some(Component::RootDir) | Some(Component::Prefix(_)) => (),
+                _ => out.push(".."),
+            },
+            comp => out.push(comp),
+        }
+    }
+    if out.as_os_str().is_empty() {
+        out.push(".");
+    }
+    out
 }
 
+#[cfg(feature = "archive")]
+pub use archive::Archive;
 pub use fs::Fs;
 pub use mem::Mem;
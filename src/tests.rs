@@ -1,4 +1,4 @@
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use indoc::indoc;
 
@@ -350,9 +350,9 @@ fn define_gates() {
 
 
         D
-        #if defined(ABC)
+
         E
-        #endif // ABC
+
         F
     "};
 
@@ -368,6 +368,19 @@ fn define_gates() {
     let node = parser.parse(Path::new("input.c")).unwrap();
 
     assert_eq!(node.collect().0, output);
+
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("input.c"), input.to_string())
+        .unwrap()
+        .build();
+    let flags = [("ABC", true), ("DEF", true)]
+        .iter()
+        .map(|(name, value)| (name.to_string(), *value))
+        .collect();
+    let parser = Parser::new(Box::new(hook), flags);
+    let node = parser.parse(Path::new("input.c")).unwrap();
+
+    assert_eq!(node.collect().0, output);
 }
 
 #[test]
@@ -417,3 +430,1785 @@ fn define_gate_include() {
 
     assert_eq!(node.collect().0, result);
 }
+
+#[test]
+fn define_uncertain_include() {
+    let main = indoc! {"
+        #ifdef UNK
+        #include \"h.h\"
+        #endif
+        #ifdef FOO
+        foo
+        #else
+        no foo
+        #endif
+        #ifdef BAR
+        bar
+        #endif
+    "};
+    let h = indoc! {"
+        #define FOO
+        #undef BAR
+    "};
+
+    let result = indoc! {"
+        #ifdef UNK
+
+        #define FOO
+        #undef BAR
+        #endif
+        #ifdef FOO
+        foo
+        #else
+        no foo
+        #endif
+        #ifdef BAR
+        bar
+        #endif
+    "};
+
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("main.c"), main.to_string())
+        .unwrap()
+        .add_file(&Path::new("h.h"), h.to_string())
+        .unwrap()
+        .build();
+    let parser = Parser::builder()
+        .add_source(hook)
+        .add_flag(String::from("BAR"), true)
+        .build();
+    let node = parser.parse(Path::new("main.c")).unwrap();
+
+    assert_eq!(node.collect().0, result);
+}
+
+#[test]
+fn if_expressions() {
+    let input = indoc! {"
+        #if defined(ABC) && !defined(DEF)
+        0
+        #endif
+        #if (1 + 2) * 3 == 9 || UNKNOWN
+        1
+        #else
+        2
+        #endif
+        #if 0 || 1
+        3
+        #endif
+        #if UNKNOWN || defined ABC
+        4
+        #endif
+        #if UNKNOWN && defined(ABC)
+        5
+        #endif
+        #if 1 / 0
+        6
+        #endif
+        #if defined(XYZ) // comment
+        7
+        #else
+        8
+        #endif
+    "};
+
+    let output = indoc! {"
+
+        0
+
+
+        1
+
+
+
+
+        3
+
+
+        4
+
+        #if UNKNOWN && defined(ABC)
+        5
+        #endif
+        #if 1 / 0
+        6
+        #endif
+        #if defined(XYZ) // comment
+        7
+        #else
+        8
+        #endif
+    "};
+
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("input.c"), input.to_string())
+        .unwrap()
+        .build();
+    let parser = Parser::builder()
+        .add_source(hook)
+        .add_flag(String::from("ABC"), true)
+        .add_flag(String::from("DEF"), false)
+        .build();
+    let node = parser.parse(Path::new("input.c")).unwrap();
+
+    assert_eq!(node.collect().0, output);
+}
+
+#[test]
+fn if_unsigned() {
+    let input = indoc! {"
+        #if 0xFFFFFFFFFFFFFFFF > 0
+        0
+        #endif
+        #if -1 > 0u
+        1
+        #endif
+        #if -1 > 0
+        2
+        #endif
+        #if 18446744073709551615 == -1
+        3
+        #endif
+        #if (1 ? -1 : 0u) > 0
+        4
+        #endif
+        #if -1 / 2
+        5
+        #elif -1 / 2u
+        6
+        #endif
+        #if 1 << 64
+        7
+        #endif
+    "};
+
+    let output = indoc! {"
+
+        0
+
+
+        1
+
+
+
+
+
+        3
+
+
+        4
+
+
+
+
+        6
+
+        #if 1 << 64
+        7
+        #endif
+    "};
+
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("input.c"), input.to_string())
+        .unwrap()
+        .build();
+    let parser = Parser::builder().add_source(hook).build();
+    let node = parser.parse(Path::new("input.c")).unwrap();
+
+    assert_eq!(node.collect().0, output);
+}
+
+#[test]
+fn elif_chains() {
+    let input = indoc! {"
+        #ifdef ABC
+        0
+        #elif defined(XYZ)
+        1
+        #else
+        2
+        #endif
+        #ifdef DEF
+        3
+        #elif defined(ABC)
+        4
+        #else
+        5
+        #endif
+        #ifdef XYZ
+        6
+        #elif defined(DEF)
+        7
+        #elif defined(ABC)
+        8
+        #else
+        9
+        #endif
+        #ifdef DEF
+        A
+        #elif defined(XYZ)
+        B
+        #elif defined(UVW)
+        C
+        #else
+        D
+        #endif
+    "};
+
+    let output = indoc! {"
+
+        0
+
+
+
+
+
+
+
+
+        4
+
+
+
+        #ifdef XYZ
+        6
+
+
+        #else
+        8
+
+
+        #endif
+
+
+        #if defined(XYZ)
+        B
+        #elif defined(UVW)
+        C
+        #else
+        D
+        #endif
+    "};
+
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("input.c"), input.to_string())
+        .unwrap()
+        .build();
+    let parser = Parser::builder()
+        .add_source(hook)
+        .add_flag(String::from("ABC"), true)
+        .add_flag(String::from("DEF"), false)
+        .build();
+    let node = parser.parse(Path::new("input.c")).unwrap();
+
+    assert_eq!(node.collect().0, output);
+}
+
+#[test]
+#[should_panic]
+fn elif_after_else() {
+    let input = indoc! {"
+        #ifdef ABC
+        #else
+        #elif defined(ABC)
+        #endif
+    "};
+
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("input.c"), input.to_string())
+        .unwrap()
+        .build();
+    let parser = Parser::builder().add_source(hook).build();
+    parser.parse(Path::new("input.c")).unwrap();
+}
+
+#[test]
+fn valued_defines() {
+    let input = indoc! {"
+        #if FEATURE_LEVEL >= 2
+        0
+        #elif FEATURE_LEVEL == 1
+        1
+        #endif
+        #ifdef TILE
+        2
+        #endif
+        #if TILE * TILE > 200 && defined(FLAG)
+        3
+        #endif
+        #if FLAG
+        4
+        #endif
+    "};
+
+    let output = indoc! {"
+
+        0
+
+
+
+
+        2
+
+
+        3
+
+        #if FLAG
+        4
+        #endif
+    "};
+
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("input.c"), input.to_string())
+        .unwrap()
+        .build();
+    let parser = Parser::builder()
+        .add_source(hook)
+        .add_define("FEATURE_LEVEL", "2")
+        .add_define("TILE", "16")
+        .add_flag(String::from("FLAG"), true)
+        .build();
+    let node = parser.parse(Path::new("input.c")).unwrap();
+
+    assert_eq!(node.collect().0, output);
+}
+
+#[test]
+fn define_substitution() {
+    let input = indoc! {"
+        float buf[TILE * TILE];
+        const char *s = \"TILE\"; // TILE
+        int x = SIZE + TILED + FLAG; /* TILE
+        TILE */ int y = TILE;
+    "};
+
+    let output = indoc! {"
+        float buf[16 * 16];
+        const char *s = \"TILE\"; // TILE
+        int x = (16 * 2) + TILED + FLAG; /* TILE
+        TILE */ int y = 16;
+    "};
+
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("input.c"), input.to_string())
+        .unwrap()
+        .build();
+    let parser = Parser::builder()
+        .add_source(hook)
+        .add_define("TILE", "16")
+        .add_define("SIZE", "(TILE * 2)")
+        .add_flag(String::from("FLAG"), true)
+        .substitute(true)
+        .build();
+    let node = parser.parse(Path::new("input.c")).unwrap();
+
+    assert_eq!(node.collect().0, output);
+}
+
+#[test]
+fn source_defines() {
+    let main = indoc! {"
+        #include <config.h>
+        #ifdef USE_FP64
+        double
+        #endif
+        #if VEC_SIZE > 2
+        vec
+        #endif
+        #ifdef TMP
+        tmp
+        #endif
+        #ifdef MAYBE
+        maybe
+        #endif
+        #ifdef ABC
+        abc
+        #endif
+    "};
+    let config = indoc! {"
+        #pragma once
+        #define USE_FP64
+        #define VEC_SIZE \\
+            (2 + /* two */ 2) // four
+        #define TMP
+        #undef TMP
+        #ifdef UNKNOWN
+        #define MAYBE
+        #undef ABC
+        #endif
+    "};
+
+    let result = indoc! {"
+
+
+        #define USE_FP64
+        #define VEC_SIZE \\
+            (2 + /* two */ 2) // four
+        #define TMP
+        #undef TMP
+        #ifdef UNKNOWN
+        #define MAYBE
+        #undef ABC
+        #endif
+
+        double
+
+
+        vec
+
+
+
+
+        #ifdef MAYBE
+        maybe
+        #endif
+        #ifdef ABC
+        abc
+        #endif
+    "};
+
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("main.c"), main.to_string())
+        .unwrap()
+        .add_file(&Path::new("config.h"), config.to_string())
+        .unwrap()
+        .build();
+    let parser = Parser::builder()
+        .add_source(hook)
+        .add_flag(String::from("ABC"), true)
+        .build();
+    let node = parser.parse(Path::new("main.c")).unwrap();
+
+    let (source, index) = node.collect();
+    assert_eq!(source, result);
+    assert_eq!(
+        index.search(15).unwrap(),
+        (Path::new("main.c").to_path_buf(), 5)
+    );
+}
+
+#[test]
+fn skip_defines() {
+    let input = indoc! {"
+        #define ABC 1
+        #ifdef XYZ
+        #define XYZ_ABC
+        #endif
+        #if ABC
+        abc
+        #endif
+    "};
+
+    let output = indoc! {"
+
+        #ifdef XYZ
+        #define XYZ_ABC
+        #endif
+
+        abc
+
+    "};
+
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("input.c"), input.to_string())
+        .unwrap()
+        .build();
+    let parser = Parser::builder()
+        .add_source(hook)
+        .keep_defines(false)
+        .build();
+    let node = parser.parse(Path::new("input.c")).unwrap();
+
+    assert_eq!(node.collect().0, output);
+}
+
+#[test]
+fn macro_expansion() {
+    let main = indoc! {"
+        #include <macros.h>
+        int a = SQR(N + 1);
+        const char *s = STR(N  +  \"x\"); // SQR(N)
+        float CAT(v, N) = MAX(1,
+            SQR(2));
+        LOG(\"%d %d\", 1, 2);
+        int SQR = EMPTY N;
+    "};
+    let macros = indoc! {"
+        #define N 4
+        #define SQR(x) ((x) * (x))
+        #define STR(x) #x
+        #define CAT(a, b) a ## b
+        #define MAX(a, b) \\
+            ((a) > (b) ? (a) : (b))
+        #define LOG(fmt, ...) printf(fmt, __VA_ARGS__)
+        #define EMPTY
+    "};
+
+    let result = indoc! {"
+        int a = ((4 + 1) * (4 + 1));
+        const char *s = \"N + \\\"x\\\"\"; // SQR(N)
+        float vN = ((1) > (((2) * (2))) ? (1) : (((2) * (2))));
+
+        printf(\"%d %d\", 1, 2);
+        int SQR =  4;
+    "};
+
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("main.c"), main.to_string())
+        .unwrap()
+        .add_file(&Path::new("macros.h"), macros.to_string())
+        .unwrap()
+        .build();
+    let parser = Parser::builder()
+        .add_source(hook)
+        .expand_macros(true)
+        .keep_defines(false)
+        .build();
+    let node = parser.parse(Path::new("main.c")).unwrap();
+
+    let (source, index) = node.collect();
+    let lines = source.lines().collect::<Vec<_>>();
+    assert!(lines[..9].iter().all(|l| l.is_empty()));
+    assert_eq!(lines[9..].join("\n") + "\n", result);
+    for (pos, line) in (9..15).zip(1..7) {
+        assert_eq!(
+            index.search(pos).unwrap(),
+            (Path::new("main.c").to_path_buf(), line)
+        );
+    }
+}
+
+#[test]
+fn macro_rescanning() {
+    let main = indoc! {"
+        #define G(x) (x + 1)
+        #define F G
+        #define f(a) a*g
+        #define g(a) f(a)
+        #define h(x) x h
+        int a = F(1);
+        int b = f(2)(9);
+        int c = h(1)(2);
+    "};
+    let result = indoc! {"
+        int a = (1 + 1);
+        int b = 2*9*g;
+        int c = 1 h(2);
+    "};
+
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("main.c"), main.to_string())
+        .unwrap()
+        .build();
+    let parser = Parser::builder()
+        .add_source(hook)
+        .expand_macros(true)
+        .keep_defines(false)
+        .build();
+    let (source, _) = parser.parse(Path::new("main.c")).unwrap().collect();
+    assert_eq!(source, String::from("\n\n\n\n\n") + result);
+}
+
+#[test]
+fn unclosed_invocation() {
+    let main = indoc! {"
+        #define H(x) x
+        int a = H(
+        #include <header.h>
+        #define K 5
+        int b = H(K);
+        #ifdef K
+        int c = H(
+        #endif
+        int d = H(K) + H(
+        K
+    "};
+    let header = indoc! {"
+        header
+    "};
+    let result = indoc! {"
+
+        int a = H(
+
+        header
+
+        int b = 5;
+
+        int c = H(
+
+        int d = 5 + H(
+        5
+    "};
+
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("main.c"), main.to_string())
+        .unwrap()
+        .add_file(&Path::new("header.h"), header.to_string())
+        .unwrap()
+        .build();
+    let parser = Parser::builder()
+        .add_source(hook)
+        .expand_macros(true)
+        .keep_defines(false)
+        .build();
+    let node = parser.parse(Path::new("main.c")).unwrap();
+    assert_eq!(node.children().count(), 1);
+    assert_eq!(node.collect().0, result);
+}
+
+#[test]
+fn error_locations() {
+    let main = indoc! {"
+        // main
+        #include \"dir/first.h\"
+    "};
+    let first = indoc! {"
+        #ifdef ABC
+        #endif
+          #include <missing.h>
+    "};
+    let second = indoc! {"
+        #endif
+    "};
+
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("main.c"), main.to_string())
+        .unwrap()
+        .add_file(&Path::new("dir/first.h"), first.to_string())
+        .unwrap()
+        .add_file(&Path::new("second.c"), second.to_string())
+        .unwrap()
+        .build();
+    let parser = Parser::builder().add_source(hook).build();
+
+    match parser.parse(Path::new("main.c")).err().unwrap() {
+        Error::NotFound { path, location } => {
+            assert_eq!(path, Path::new("missing.h"));
+            let location = location.unwrap();
+            assert_eq!(location.path, Path::new("dir/first.h"));
+            assert_eq!((location.line, location.column), (2, 12));
+            assert_eq!(location.stack, vec![(Path::new("main.c").to_path_buf(), 1)]);
+        }
+        e => panic!("{:?}", e),
+    }
+
+    match parser.parse(Path::new("second.c")).err().unwrap() {
+        Error::UnexpectedEndif { location } => {
+            assert_eq!(location, Location::new(Path::new("second.c"), 0, 0));
+        }
+        e => panic!("{:?}", e),
+    }
+
+    match parser.parse(Path::new("unknown.c")).err().unwrap() {
+        Error::NotFound { path, location } => {
+            assert_eq!(path, Path::new("unknown.c"));
+            assert_eq!(location, None);
+        }
+        e => panic!("{:?}", e),
+    }
+}
+
+#[test]
+fn recursion_error() {
+    let first = indoc! {"
+        #include <second.h>
+    "};
+    let second = indoc! {"
+
+        #include <first.h>
+    "};
+
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("first.h"), first.to_string())
+        .unwrap()
+        .add_file(&Path::new("second.h"), second.to_string())
+        .unwrap()
+        .build();
+    let parser = Parser::builder().add_source(hook).build();
+
+    match parser.parse(Path::new("first.h")).err().unwrap() {
+        Error::Recursion { path, location } => {
+            assert_eq!(path, Path::new("first.h"));
+            let location = location.unwrap();
+            assert_eq!(
+                (location.path.as_path(), location.line),
+                (Path::new("second.h"), 1)
+            );
+            assert_eq!(location.stack.len(), 3);
+        }
+        e => panic!("{:?}", e),
+    }
+}
+
+#[test]
+fn line_directives() {
+    let main = indoc! {"
+        00
+        #include \"h01.h\"
+        02
+    "};
+    let h01 = indoc! {"
+        10
+        11
+    "};
+
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("dir/main.c"), main.to_string())
+        .unwrap()
+        .add_file(&Path::new("dir/h01.h"), h01.to_string())
+        .unwrap()
+        .build();
+    let parser = Parser::builder().add_source(hook).build();
+    let node = parser.parse(Path::new("dir/main.c")).unwrap();
+
+    let (source, index) = node.collect_with_lines(&LineNames::Relative("dir".into()));
+    assert_eq!(
+        source,
+        indoc! {"
+            #line 1 \"main.c\"
+            00
+
+            #line 1 \"h01.h\"
+            10
+            11
+            #line 3 \"main.c\"
+            02
+        "}
+    );
+    for (pos, line) in source.lines().enumerate() {
+        let (name, lpos) = index.search(pos).unwrap();
+        if !line.is_empty() && !line.starts_with('#') {
+            let n = line.parse::<usize>().unwrap();
+            assert_eq!(
+                name.file_name().unwrap().to_string_lossy(),
+                if n / 10 == 0 { "main.c" } else { "h01.h" }
+            );
+            assert_eq!(n % 10, lpos);
+        }
+    }
+
+    let (source, index) = node.collect_with_lines(&LineNames::Ids);
+    assert_eq!(
+        source
+            .lines()
+            .filter(|l| l.starts_with('#'))
+            .collect::<Vec<_>>(),
+        vec!["#line 1 0", "#line 1 1", "#line 3 0"],
+    );
+    assert_eq!(
+        index.files(),
+        vec![
+            Path::new("dir/main.c").to_path_buf(),
+            Path::new("dir/h01.h").to_path_buf()
+        ],
+    );
+}
+
+#[test]
+fn log_rewriting() {
+    let main = indoc! {"
+        00
+        #include <h01.h>
+        02
+    "};
+    let h01 = indoc! {"
+        10
+        11
+    "};
+
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("main.c"), main.to_string())
+        .unwrap()
+        .add_file(&Path::new("h01.h"), h01.to_string())
+        .unwrap()
+        .build();
+    let parser = Parser::builder().add_source(hook).build();
+    let (_, index) = parser.parse(Path::new("main.c")).unwrap().collect();
+
+    let log = indoc! {"
+        <kernel>:4:7: error: use of undeclared identifier 'x'
+        :5:1: warning: something
+        \"/tmp/OCL1234T5.cl\", line 3: error: identifier \"y\" is undefined
+        /tmp/comgr-01/input/CompileSource:1:2: note: here
+        1:4:3: error: expected ';'
+        /home/user/.cache/pocl/program.cl:3:9: error: oops
+        <kernel>:100:1: error: out of range
+        opencl-c.h:1234:5: note: candidate function
+    "};
+    let result = indoc! {"
+        h01.h:2:7: error: use of undeclared identifier 'x'
+        main.c:3:1: warning: something
+        h01.h:1: error: identifier \"y\" is undefined
+        main.c:1:2: note: here
+        h01.h:2:3: error: expected ';'
+        h01.h:1:9: error: oops
+        <kernel>:100:1: error: out of range
+        opencl-c.h:1234:5: note: candidate function
+    "};
+    assert_eq!(rewrite_log(log, &index), result);
+
+    let log = ":3:1: error: something";
+    assert_eq!(
+        rewrite_log_with(log, &index, &[LogFormat::Nvidia]),
+        "h01.h:1:1: error: something"
+    );
+    assert_eq!(rewrite_log_with(log, &index, &[LogFormat::Clang]), log);
+
+    // Column zero is not mapped, but kept as is.
+    assert_eq!(
+        rewrite_log("<kernel>:4:0: error: something", &index),
+        "h01.h:2:0: error: something"
+    );
+}
+
+#[test]
+fn dependencies() {
+    let main = indoc! {"
+        #include <a.h>
+        #include <b.h>
+        #include <a.h>
+        main
+    "};
+    let a = indoc! {"
+        #pragma once
+        #include <empty.h>
+        a
+    "};
+
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("main.c"), main.to_string())
+        .unwrap()
+        .add_file(&Path::new("a.h"), a.to_string())
+        .unwrap()
+        .add_file(&Path::new("b h.h"), String::from("b\n"))
+        .unwrap()
+        .add_file(&Path::new("b.h"), String::from("#include \"b h.h\"\n"))
+        .unwrap()
+        .add_file(&Path::new("empty.h"), String::new())
+        .unwrap()
+        .build();
+    let parser = Parser::builder().add_source(hook).build();
+    let node = parser.parse(Path::new("main.c")).unwrap();
+
+    assert_eq!(
+        node.files(),
+        ["main.c", "a.h", "empty.h", "b.h", "b h.h"]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>()
+    );
+    assert_eq!(
+        node.depfile(Path::new("out.cl")),
+        indoc! {"
+            out.cl: main.c a.h empty.h b.h b\\ h.h
+
+            a.h:
+
+            empty.h:
+
+            b.h:
+
+            b\\ h.h:
+        "}
+    );
+}
+
+/// Creates empty temporary directory unique for the test and the process.
+fn test_dir(name: &str) -> PathBuf {
+    let dir =
+        std::env::temp_dir().join(format!("ocl-include-test-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+#[test]
+fn build_bundle() {
+    let out_dir = test_dir("bundle").join("out");
+
+    let bundled = build::Bundle::new("examples/main.c")
+        .include_dir("examples")
+        .write_index(true)
+        .out_dir(&out_dir)
+        .rerun_if_changed(false)
+        .bundle()
+        .unwrap();
+
+    assert_eq!(bundled.source, out_dir.join("main.c"));
+    assert_eq!(bundled.index, Some(out_dir.join("main.idx")));
+    let index = Index::from_bytes(&std::fs::read(out_dir.join("main.idx")).unwrap()).unwrap();
+    assert!(index.search(3).unwrap().0.ends_with("header.h"));
+    assert_eq!(bundled.files.len(), 2);
+    assert!(bundled.files[1].ends_with("header.h"));
+    let source = std::fs::read_to_string(&bundled.source).unwrap();
+    assert!(source.contains("static const int RET_CODE = 0;"));
+    assert!(source.contains("return RET_CODE;"));
+
+    match build::Bundle::new("examples/main.c")
+        .include_dir("missing")
+        .out_dir(&out_dir)
+        .rerun_if_changed(false)
+        .bundle()
+    {
+        Err(build::BuildError::Io {
+            path: Some(path), ..
+        }) => assert!(path.ends_with("missing")),
+        r => panic!("{:?}", r.err()),
+    }
+    if std::env::var_os("OUT_DIR").is_none() {
+        match build::Bundle::new("examples/main.c")
+            .include_dir("examples")
+            .rerun_if_changed(false)
+            .bundle()
+        {
+            Err(build::BuildError::Io { path: None, .. }) => (),
+            r => panic!("{:?}", r.err()),
+        }
+    }
+}
+
+#[test]
+fn index_serialization() {
+    let main = indoc! {"
+        #include <h1.h>
+        main
+    "};
+    let h1 = indoc! {"
+        h1
+        #include <h2.h>
+        h1
+    "};
+
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("main.c"), main.to_string())
+        .unwrap()
+        .add_file(&Path::new("h1.h"), h1.to_string())
+        .unwrap()
+        .add_file(&Path::new("h2.h"), String::from("h2\n"))
+        .unwrap()
+        .build();
+    let parser = Parser::builder().add_source(hook).build();
+    let (_, index) = parser.parse(Path::new("main.c")).unwrap().collect();
+
+    let bytes = index.to_bytes();
+    assert!(bytes.starts_with(b"OCLI"));
+    assert_eq!(
+        Index::from_bytes(&bytes).unwrap().segments(),
+        index.segments()
+    );
+
+    assert!(Index::from_bytes(&bytes[..(bytes.len() - 1)]).is_err());
+    assert!(Index::from_bytes(b"OCLI\x01\x00\x01\x00\x00\x01\x00").is_err());
+    // Segment sizes overflow, column mapping and directive lines are out of range.
+    for (bad, msg) in [
+        (
+            &b"OCLI\x01\x01\x01a\x02\x00\x00\xff\xff\xff\xff\xff\xff\xff\xff\xff\x01\x00\x00\x01\x00\x00"[..],
+            "too large",
+        ),
+        (
+            &b"OCLI\x01\x01\x01a\x01\x00\x00\x01\x01\x01\x00\x00\x00"[..],
+            "column mapping",
+        ),
+        (
+            &b"OCLI\x01\x01\x01a\x01\x00\x00\x01\x00\x01\x01"[..],
+            "directive",
+        ),
+    ] {
+        let err = Index::from_bytes(bad).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains(msg), "{}", err);
+    }
+    let mut newer = bytes.clone();
+    newer[4] = 2;
+    let err = Index::from_bytes(&newer).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    assert!(err.to_string().contains("version 2"));
+
+    let mut renamed = Index::from_bytes(&bytes).unwrap();
+    renamed.map_files(|path| Path::new("dir").join(path));
+    assert_eq!(renamed.search(3).unwrap(), (PathBuf::from("dir/h2.h"), 0));
+    assert_eq!(renamed.segments().len(), index.segments().len());
+
+    #[cfg(feature = "serde")]
+    {
+        let json = serde_json::to_string(&index).unwrap();
+        assert_eq!(
+            json,
+            r#"{"files":["main.c","h1.h","h2.h"],"segments":[[0,0,1],[1,0,2],[2,0,1],[1,2,1],[0,1,1]]}"#
+        );
+        let restored: Index = serde_json::from_str(&json).unwrap();
+        assert_eq!(restored.segments(), index.segments());
+    }
+}
+
+#[test]
+fn column_mapping() {
+    let main = indoc! {"
+        #define SQR(x) ((x) * (x))
+        #define N 16
+        int a = SQR(N) + b;
+        #if 0
+        #elif defined(X)
+        #endif
+        int c = SQR(
+            N) + d;
+    "};
+
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("main.c"), main.to_string())
+        .unwrap()
+        .build();
+    let parser = Parser::builder()
+        .add_source(hook)
+        .expand_macros(true)
+        .build();
+    let (source, index) = parser.parse(Path::new("main.c")).unwrap().collect();
+    let lines = source.lines().collect::<Vec<_>>();
+    assert_eq!(lines[2], "int a = ((16) * (16)) + b;");
+    assert_eq!(lines[4], "#if defined(X)");
+    assert_eq!(lines[6], "int c = ((16) * (16)) + d;");
+
+    let main = Path::new("main.c").to_path_buf();
+    assert_eq!(index.search_position(2, 4), Some((main.clone(), 2, 4)));
+    assert_eq!(index.search_position(2, 10), Some((main.clone(), 2, 8)));
+    assert_eq!(index.search_position(2, 24), Some((main.clone(), 2, 17)));
+    assert_eq!(index.search_position(4, 4), Some((main.clone(), 4, 6)));
+    assert_eq!(index.search_position(6, 24), Some((main.clone(), 7, 9)));
+    assert_eq!(index.search_position(7, 3), Some((main.clone(), 7, 3)));
+
+    let restored = Index::from_bytes(&index.to_bytes()).unwrap();
+    assert_eq!(restored.search_position(6, 24), Some((main, 7, 9)));
+
+    assert_eq!(
+        rewrite_log(
+            "<kernel>:3:25: error: use of undeclared identifier 'b'",
+            &index
+        ),
+        "main.c:3:18: error: use of undeclared identifier 'b'"
+    );
+}
+
+#[test]
+fn reverse_mapping() {
+    let main = indoc! {"
+        00
+        #include <h01.h>
+        02
+        #include <h01.h>
+    "};
+    let h01 = indoc! {"
+        10
+        11
+    "};
+
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("main.c"), main.to_string())
+        .unwrap()
+        .add_file(&Path::new("h01.h"), h01.to_string())
+        .unwrap()
+        .build();
+    let parser = Parser::builder().add_source(hook).build();
+    let node = parser.parse(Path::new("main.c")).unwrap();
+
+    let (_, index) = node.collect();
+    assert_eq!(index.search_origin(Path::new("h01.h"), 1), vec![3..4, 7..8]);
+    assert_eq!(index.search_origin(Path::new("main.c"), 2), vec![4..5]);
+    assert!(index.search_origin(Path::new("h01.h"), 2).is_empty());
+    assert!(index.search_origin(Path::new("h02.h"), 0).is_empty());
+
+    let (_, index) = node.collect_with_lines(&LineNames::Paths);
+    assert_eq!(
+        index.search_origin(Path::new("h01.h"), 0),
+        vec![4..5, 10..11]
+    );
+    let bytes = index.to_bytes();
+    assert_eq!(
+        Index::from_bytes(&bytes)
+            .unwrap()
+            .search_origin(Path::new("h01.h"), 0),
+        vec![4..5, 10..11]
+    );
+    for range in index.search_origin(Path::new("h01.h"), 1) {
+        for pos in range {
+            assert_eq!(
+                index.search(pos),
+                Some((Path::new("h01.h").to_path_buf(), 1))
+            );
+        }
+    }
+
+    // Lines of multiline invocation are found in the line it is expanded into.
+    let main = indoc! {"
+        #define ADD(a, b) ((a) + (b))
+        int x = ADD(1,
+                    2) + 1;
+        int y;
+    "};
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("main.c"), main.to_string())
+        .unwrap()
+        .build();
+    let parser = Parser::builder()
+        .add_source(hook)
+        .expand_macros(true)
+        .build();
+    let (code, index) = parser.parse(Path::new("main.c")).unwrap().collect();
+    assert_eq!(code.lines().nth(1), Some("int x = ((1) + (2)) + 1;"));
+    let main = Path::new("main.c");
+    assert_eq!(
+        index.search_position(1, 22),
+        Some((main.to_path_buf(), 2, 17))
+    );
+    assert_eq!(index.search_origin(main, 1), vec![1..2]);
+    assert_eq!(index.search_origin(main, 2), vec![1..2]);
+    assert_eq!(index.search_origin(main, 3), vec![3..4]);
+}
+
+#[test]
+fn source_map() {
+    let main = indoc! {"
+        00
+        #include <h01.h>
+        02
+    "};
+
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("main.c"), main.to_string())
+        .unwrap()
+        .add_file(&Path::new("h01.h"), String::from("10\n\"11\"\n"))
+        .unwrap()
+        .build();
+    let hook = std::sync::Arc::new(hook);
+    let parser = Parser::builder().add_source(hook.clone()).build();
+    let (_, index) = parser.parse(Path::new("main.c")).unwrap().collect();
+
+    assert_eq!(
+        index.source_map("out.cl"),
+        concat!(
+            r#"{"version":3,"file":"out.cl","sources":["main.c","h01.h"],"#,
+            r#""names":[],"mappings":"AAAA;AACA;ACDA;AACA;ADCA"}"#,
+        )
+    );
+    assert_eq!(
+        index.source_map_with_contents("out.cl", hook.as_ref()),
+        concat!(
+            r#"{"version":3,"file":"out.cl","sources":["main.c","h01.h"],"#,
+            r#""sourcesContent":["00\n#include <h01.h>\n02\n","10\n\"11\"\n"],"#,
+            r#""names":[],"mappings":"AAAA;AACA;ACDA;AACA;ADCA"}"#,
+        )
+    );
+
+    let hook = source::Mem::builder()
+        .add_file(
+            &Path::new("main.c"),
+            String::from("#define N 16\nint a = N + b;\n"),
+        )
+        .unwrap()
+        .build();
+    let parser = Parser::builder()
+        .add_source(hook)
+        .expand_macros(true)
+        .build();
+    let (_, index) = parser.parse(Path::new("main.c")).unwrap().collect();
+    // "int a = " -> 0:0, "16" -> 0:8, " + b;" -> 0:9
+    assert!(index
+        .source_map("out.cl")
+        .ends_with(r#""mappings":"AAAA;AACA,QAAQ,EAAC"}"#));
+}
+
+#[test]
+fn traversal() {
+    let main = indoc! {"
+        00
+        #include <h1.h>
+        02
+        #include <h2.h>
+    "};
+    let h1 = indoc! {"
+        10
+        #include <h2.h>
+    "};
+
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("main.c"), main.to_string())
+        .unwrap()
+        .add_file(&Path::new("h1.h"), h1.to_string())
+        .unwrap()
+        .add_file(&Path::new("h2.h"), String::from("20\n"))
+        .unwrap()
+        .build();
+    let parser = Parser::builder().add_source(hook).build();
+    let node = parser.parse(Path::new("main.c")).unwrap();
+
+    assert_eq!(node.lines().collect::<Vec<_>>(), ["00", "", "02", ""]);
+    assert_eq!(
+        node.children()
+            .map(|(child, pos)| (child.name().to_path_buf(), pos))
+            .collect::<Vec<_>>(),
+        [(PathBuf::from("h1.h"), 1), (PathBuf::from("h2.h"), 3)]
+    );
+
+    let mut visited = Vec::new();
+    node.visit(|node, depth| visited.push((node.name().to_path_buf(), depth)));
+    assert_eq!(
+        visited,
+        [
+            (PathBuf::from("main.c"), 0),
+            (PathBuf::from("h1.h"), 1),
+            (PathBuf::from("h2.h"), 2),
+            (PathBuf::from("h2.h"), 1),
+        ]
+    );
+}
+
+#[test]
+fn include_graph() {
+    let main = indoc! {"
+        #include <h1.h>
+        #include <h2.h>
+        main
+    "};
+    let h1 = indoc! {"
+        #pragma once
+        #include <h2.h>
+    "};
+    let h2 = indoc! {"
+        #include <h1.h>
+        h2
+    "};
+
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("main.c"), main.to_string())
+        .unwrap()
+        .add_file(&Path::new("h1.h"), h1.to_string())
+        .unwrap()
+        .add_file(&Path::new("h2.h"), h2.to_string())
+        .unwrap()
+        .build();
+    let parser = Parser::builder().add_source(hook).build();
+    let node = parser.parse(Path::new("main.c")).unwrap();
+
+    assert_eq!(
+        node.include_tree(GraphFormat::Dot),
+        indoc! {r#"
+            digraph includes {
+                n0 [label="main.c\n3 lines"];
+                n1 [label="h1.h\n2 lines"];
+                n2 [label="h2.h\n2 lines"];
+                n3 [label="h1.h", style=dashed];
+                n2 -> n3 [label="1", style=dashed];
+                n1 -> n2 [label="2"];
+                n0 -> n1 [label="1"];
+                n4 [label="h2.h\n2 lines"];
+                n5 [label="h1.h", style=dashed];
+                n4 -> n5 [label="1", style=dashed];
+                n0 -> n4 [label="2"];
+            }
+        "#}
+    );
+    assert_eq!(
+        node.include_graph(GraphFormat::Dot),
+        indoc! {r#"
+            digraph includes {
+                f0 [label="main.c\n3 lines"];
+                f1 [label="h1.h\n2 lines"];
+                f2 [label="h2.h\n2 lines"];
+                f0 -> f1 [label="1"];
+                f1 -> f2 [label="2"];
+                f2 -> f1 [label="1", style=dashed];
+                f0 -> f2 [label="2"];
+            }
+        "#}
+    );
+    assert_eq!(
+        node.include_graph(GraphFormat::Json),
+        concat!(
+            r#"{"files":[{"path":"main.c","lines":3},{"path":"h1.h","lines":2},{"path":"h2.h","lines":2}],"#,
+            r#""edges":[{"from":0,"to":1,"line":1,"skipped":false},{"from":1,"to":2,"line":2,"skipped":false},"#,
+            r#"{"from":2,"to":1,"line":1,"skipped":true},{"from":0,"to":2,"line":2,"skipped":false}]}"#,
+        )
+    );
+
+    #[cfg(feature = "serde")]
+    {
+        let tree: serde_json::Value =
+            serde_json::from_str(&node.include_tree(GraphFormat::Json)).unwrap();
+        assert_eq!(tree["includes"][1]["line"], 2);
+        assert_eq!(tree["includes"][1]["node"]["path"], "h2.h");
+        assert_eq!(tree["includes"][1]["node"]["skipped"][0]["path"], "h1.h");
+    }
+}
+
+#[test]
+fn parallel_parsing() {
+    fn assert_send_sync<T: Send + Sync>() {}
+    assert_send_sync::<Parser>();
+    assert_send_sync::<Node>();
+    assert_send_sync::<Index>();
+    assert_send_sync::<source::Fs>();
+
+    let mut builder = source::Mem::builder();
+    for i in 0..8 {
+        let name = format!("main{}.c", i);
+        let text = format!("#include <header.h>\n{}\n", i);
+        builder = builder.add_file(&name, text).unwrap();
+    }
+    let hook = builder
+        .add_file(&"header.h", String::from("header\n"))
+        .unwrap()
+        .build();
+    let parser = std::sync::Arc::new(Parser::builder().add_source(hook).build());
+
+    let threads = (0..8)
+        .map(|i| {
+            let parser = parser.clone();
+            std::thread::spawn(move || {
+                let node = parser.parse(Path::new(&format!("main{}.c", i))).unwrap();
+                node.collect()
+            })
+        })
+        .collect::<Vec<_>>();
+    for (i, thread) in threads.into_iter().enumerate() {
+        let (source, index) = thread.join().unwrap();
+        assert_eq!(source, format!("\nheader\n{}\n", i));
+        assert_eq!(index.search(1), Some((PathBuf::from("header.h"), 0)));
+    }
+
+    /// Source that answers only when two files are being read at the same time.
+    #[derive(Default)]
+    struct Rendezvous {
+        readers: std::sync::Mutex<usize>,
+        cond: std::sync::Condvar,
+    }
+    impl Source for Rendezvous {
+        fn read(&self, path: &Path, _: Option<&Path>) -> std::io::Result<(PathBuf, String)> {
+            let mut readers = self.readers.lock().unwrap();
+            *readers += 1;
+            self.cond.notify_all();
+            let timeout = std::time::Duration::from_secs(10);
+            let (_readers, res) = self
+                .cond
+                .wait_timeout_while(readers, timeout, |readers| *readers < 2)
+                .unwrap();
+            if res.timed_out() {
+                return Err(std::io::ErrorKind::TimedOut.into());
+            }
+            Ok((path.to_path_buf(), String::from("text\n")))
+        }
+    }
+
+    // Parses must run concurrently to finish.
+    let parser = std::sync::Arc::new(Parser::builder().add_source(Rendezvous::default()).build());
+    let threads = (0..2)
+        .map(|i| {
+            let parser = parser.clone();
+            std::thread::spawn(move || parser.parse(Path::new(&format!("main{}.c", i))).is_ok())
+        })
+        .collect::<Vec<_>>();
+    for thread in threads {
+        assert!(thread.join().unwrap());
+    }
+}
+
+#[test]
+fn independent_parses() {
+    let header = indoc! {"
+        #pragma once
+        #define VALUE 1
+        header
+    "};
+    let first = indoc! {"
+        #include <header.h>
+        first
+    "};
+    let second = indoc! {"
+        #include <header.h>
+        #if VALUE
+        second
+        #endif
+    "};
+
+    let hook = source::Mem::builder()
+        .add_file(&Path::new("first.c"), first.to_string())
+        .unwrap()
+        .add_file(&Path::new("second.c"), second.to_string())
+        .unwrap()
+        .add_file(&Path::new("header.h"), header.to_string())
+        .unwrap()
+        .build();
+    let parser = Parser::builder()
+        .add_source(hook)
+        .keep_defines(false)
+        .build();
+
+    for _ in 0..2 {
+        let (source, _) = parser.parse(Path::new("first.c")).unwrap().collect();
+        assert_eq!(source, "\n\n\nheader\nfirst\n");
+        let (source, _) = parser.parse(Path::new("second.c")).unwrap().collect();
+        assert_eq!(source, "\n\n\nheader\n\nsecond\n\n");
+    }
+
+    let mut session = parser.session();
+    let (source, _) = session.parse(Path::new("first.c")).unwrap().collect();
+    assert_eq!(source, "\n\n\nheader\nfirst\n");
+    let node = session.parse(Path::new("second.c")).unwrap();
+    assert_eq!(node.collect().0, "\n\nsecond\n\n");
+    assert_eq!(node.skipped().count(), 1);
+
+    // Root already included with `#pragma once` is empty.
+    let node = session.parse(Path::new("header.h")).unwrap();
+    let (source, index) = node.collect();
+    assert_eq!(source, "");
+    assert!(index.search(0).is_none());
+}
+
+#[test]
+fn path_normalization() {
+    let main = indoc! {"
+        #include \"./lib/util.h\"
+        #include \"lib/inner/other.h\"
+        #include <../examples/header.h>
+        #include <header.h>
+    "};
+    let util = indoc! {"
+        #pragma once
+        util
+    "};
+    let other = indoc! {"
+        #include \"../util.h\"
+        #include \"./../../lib/util.h\"
+        other
+    "};
+
+    assert_eq!(
+        source::normalize(Path::new("./a/./b/../c")),
+        Path::new("a/c")
+    );
+    assert_eq!(source::normalize(Path::new("a/../../b")), Path::new("../b"));
+    assert_eq!(source::normalize(Path::new("/../a/..")), Path::new("/"));
+    assert_eq!(source::normalize(Path::new("a/..")), Path::new("."));
+
+    let parser = Parser::builder()
+        .add_source(
+            source::Mem::builder()
+                .add_file(&Path::new("main.c"), main.to_string())
+                .unwrap()
+                .add_file(&Path::new("lib/util.h"), util.to_string())
+                .unwrap()
+                .add_file(&Path::new("./lib/inner/other.h"), other.to_string())
+                .unwrap()
+                .build(),
+        )
+        .add_source(
+            source::Fs::builder()
+                .include_dir("examples")
+                .unwrap()
+                .canonicalize(true)
+                .build(),
+        )
+        .build();
+    let node = parser.parse(Path::new("main.c")).unwrap();
+
+    let files = node.files();
+    assert_eq!(files.len(), 4);
+    assert!(files.contains(&PathBuf::from("lib/util.h")));
+    assert!(files.contains(&PathBuf::from("lib/inner/other.h")));
+    assert!(files.contains(&Path::new("examples/header.h").canonicalize().unwrap()));
+    assert_eq!(
+        node.collect().0,
+        "\n\nutil\n\n\n\nother\n\n\n\nstatic const int RET_CODE = 0;\n\n"
+    );
+
+    // File is read from the path resolved by the system, though its name is normalized lexically.
+    #[cfg(unix)]
+    {
+        let dir = test_dir("normalization");
+        std::fs::create_dir_all(dir.join("inc")).unwrap();
+        std::fs::create_dir_all(dir.join("other/sub")).unwrap();
+        std::fs::write(dir.join("inc/a.h"), "inc\n").unwrap();
+        std::fs::write(dir.join("other/a.h"), "other\n").unwrap();
+        std::os::unix::fs::symlink(dir.join("other/sub"), dir.join("inc/link")).unwrap();
+
+        let parser = Parser::builder()
+            .add_source(
+                source::Fs::builder()
+                    .include_dir(dir.join("inc"))
+                    .unwrap()
+                    .build(),
+            )
+            .build();
+        let node = parser.parse(&dir.join("inc/link/../a.h")).unwrap();
+        assert_eq!(node.name(), dir.join("inc/a.h"));
+        assert_eq!(node.collect().0, "other\n");
+    }
+}
+
+#[test]
+fn cache_invalidation() {
+    let dir = test_dir("cache");
+    let header = dir.join("header.h");
+    std::fs::write(&header, "old\n").unwrap();
+
+    let fs = std::sync::Arc::new(source::Fs::builder().include_dir(&dir).unwrap().build());
+    let parser = Parser::builder().add_source(fs.clone()).build();
+    let parse = || parser.parse(Path::new("header.h")).unwrap().collect().0;
+    assert_eq!(parse(), "old\n");
+    assert!(fs.changed().is_empty());
+
+    std::fs::write(&header, "modified\n").unwrap();
+    assert_eq!(fs.changed(), vec![header.clone()]);
+    assert_eq!(parse(), "old\n");
+    assert!(fs.invalidate(&header));
+    assert!(!fs.invalidate(&header));
+    assert_eq!(parse(), "modified\n");
+    assert!(fs.changed().is_empty());
+
+    fs.clear();
+    std::fs::write(&header, "new\n").unwrap();
+    assert_eq!(parse(), "new\n");
+
+    let fs = source::Fs::builder()
+        .include_dir(&dir)
+        .unwrap()
+        .check_modified(true)
+        .build();
+    let parser = Parser::builder().add_source(fs).build();
+    let parse = || parser.parse(Path::new("header.h")).unwrap().collect().0;
+    assert_eq!(parse(), "new\n");
+    std::fs::write(&header, "newest\n").unwrap();
+    assert_eq!(parse(), "newest\n");
+}
+
+#[test]
+fn watch() {
+    let dir = test_dir("watch");
+    let main = dir.join("main.c");
+    let header = dir.join("header.h");
+    std::fs::write(&main, "#include \"header.h\"\nmain\n").unwrap();
+    std::fs::write(&header, "old\n").unwrap();
+
+    let parser = Parser::builder().add_source(source::Fs::new()).build();
+    let mut watcher = watch::Watcher::new(parser, &main);
+    assert_eq!(watcher.poll().unwrap().unwrap().0, "\nold\nmain\n");
+    assert_eq!(watcher.files().count(), 2);
+    assert!(watcher.poll().is_none());
+
+    std::fs::write(&header, "modified\n").unwrap();
+    let (source, index) = watcher.poll().unwrap().unwrap();
+    assert_eq!(source, "\nmodified\nmain\n");
+    assert_eq!(index.search(1).unwrap().0, header);
+    assert!(watcher.poll().is_none());
+
+    // The missing header is watched, so its creation triggers parsing.
+    std::fs::write(&main, "#include \"missing.h\"\n").unwrap();
+    assert!(watcher.poll().unwrap().is_err());
+    assert!(watcher.poll().is_none());
+    let missing = dir.join("missing.h");
+    assert!(watcher.files().any(|path| path == missing));
+    std::fs::write(&missing, "created\n").unwrap();
+    assert_eq!(watcher.poll().unwrap().unwrap().0, "\ncreated\n");
+
+    std::fs::write(&main, "main\n").unwrap();
+    assert_eq!(watcher.poll().unwrap().unwrap().0, "main\n");
+    assert_eq!(watcher.files().collect::<Vec<_>>(), vec![main.as_path()]);
+
+    // The first result is delivered at once.
+    let parser = Parser::builder().add_source(source::Fs::new()).build();
+    let mut results = Vec::new();
+    watch::Watcher::new(parser, &main).run(|res| {
+        results.push(res.unwrap().0);
+        false
+    });
+    assert_eq!(results, vec![String::from("main\n")]);
+
+    let parser = Parser::builder().add_source(source::Fs::new()).build();
+    let receiver = watch::Watcher::new(parser, &main).spawn();
+    assert_eq!(receiver.recv().unwrap().unwrap().0, "main\n");
+}
+
+#[test]
+fn sandbox() {
+    let dir = test_dir("sandbox");
+    let inc = dir.join("include");
+    std::fs::create_dir_all(&inc).unwrap();
+    std::fs::write(inc.join("header.h"), "header\n").unwrap();
+    std::fs::write(dir.join("secret.h"), "secret\n").unwrap();
+
+    let parse = |main: String, sandbox: bool| {
+        let parser = Parser::builder()
+            .add_source(
+                source::Mem::builder()
+                    .add_file(&Path::new("main.c"), main)
+                    .unwrap()
+                    .build(),
+            )
+            .add_source(
+                source::Fs::builder()
+                    .include_dir(&inc)
+                    .unwrap()
+                    .sandbox(sandbox)
+                    .build(),
+            )
+            .build();
+        parser
+            .parse(Path::new("main.c"))
+            .map(|node| node.collect().0)
+    };
+
+    assert_eq!(
+        parse("#include <header.h>\n".to_string(), true).unwrap(),
+        "\nheader\n"
+    );
+    assert_eq!(
+        parse("#include <../include/header.h>\n".to_string(), true).unwrap(),
+        "\nheader\n"
+    );
+
+    let secret = dir.join("secret.h");
+    for main in [
+        "#include <../secret.h>\n".to_string(),
+        format!("#include \"{}\"\n", secret.display()),
+    ] {
+        assert_eq!(parse(main.clone(), false).unwrap(), "\nsecret\n");
+        match parse(main, true).unwrap_err() {
+            Error::Forbidden { location, .. } => {
+                assert_eq!(location.unwrap().path, Path::new("main.c"));
+            }
+            e => panic!("{:?}", e),
+        }
+    }
+
+    // Paths outside are refused whether they exist or not, so nothing is revealed about them.
+    std::fs::create_dir_all(dir.join("sub")).unwrap();
+    for main in [
+        "#include \"../nothere.h\"\n".to_string(),
+        "#include <../sub>\n".to_string(),
+        format!("#include \"{}\"\n", inc.join("header.h").display()),
+    ] {
+        match parse(main, true).unwrap_err() {
+            Error::Forbidden { .. } => (),
+            e => panic!("{:?}", e),
+        }
+    }
+    match parse("#include <missing.h>\n".to_string(), true).unwrap_err() {
+        Error::NotFound { .. } => (),
+        e => panic!("{:?}", e),
+    }
+
+    #[cfg(unix)]
+    {
+        let link = inc.join("link.h");
+        if !link.exists() {
+            std::os::unix::fs::symlink(&secret, &link).unwrap();
+        }
+        match parse("#include <link.h>\n".to_string(), true).unwrap_err() {
+            Error::Forbidden { .. } => (),
+            e => panic!("{:?}", e),
+        }
+    }
+
+    // Absolute path of the main file inside of the include directory is allowed.
+    let parser = Parser::builder()
+        .add_source(
+            source::Fs::builder()
+                .include_dir(&inc)
+                .unwrap()
+                .sandbox(true)
+                .build(),
+        )
+        .build();
+    let node = parser.parse(&inc.join("header.h")).unwrap();
+    assert_eq!(node.collect().0, "header\n");
+    match parser
+        .parse(&secret)
+        .map(|node| node.collect().0)
+        .unwrap_err()
+    {
+        Error::Forbidden { path, location } => {
+            assert_eq!(path, secret);
+            assert!(location.is_none());
+        }
+        e => panic!("{:?}", e),
+    }
+}
+
+#[cfg(feature = "archive")]
+#[test]
+fn archive_source() {
+    use std::io::{Cursor, Write};
+
+    let files = [
+        ("main.c", "#include \"lib/util.h\"\nmain\n"),
+        ("lib/util.h", "#include \"inner.h\"\nutil\n"),
+        ("lib/inner.h", "inner\n"),
+    ];
+    let result = "\n\ninner\nutil\nmain\n";
+
+    let mut zip = zip::ZipWriter::new(Cursor::new(Vec::new()));
+    for (name, text) in files.iter() {
+        zip.start_file(*name, zip::write::FileOptions::default())
+            .unwrap();
+        zip.write_all(text.as_bytes()).unwrap();
+    }
+    let zip = zip.finish().unwrap().into_inner();
+
+    let mut tar = tar::Builder::new(Vec::new());
+    for (name, text) in files.iter() {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(text.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, format!("./{}", name), text.as_bytes())
+            .unwrap();
+    }
+    let tar = tar.into_inner().unwrap();
+
+    let mut gz = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    gz.write_all(&tar).unwrap();
+    let tar_gz = gz.finish().unwrap();
+
+    let path = test_dir("archive").join("archive.zip");
+    std::fs::write(&path, &zip).unwrap();
+
+    for archive in [
+        source::Archive::from_bytes(&zip).unwrap(),
+        source::Archive::from_bytes(&tar).unwrap(),
+        source::Archive::from_bytes(&tar_gz).unwrap(),
+        source::Archive::open(&path).unwrap(),
+    ] {
+        let parser = Parser::builder().add_source(archive).build();
+        let node = parser.parse(Path::new("main.c")).unwrap();
+        let (source, index) = node.collect();
+        assert_eq!(source, result);
+        assert_eq!(index.search(2).unwrap().0, Path::new("lib/inner.h"));
+        assert!(parser.parse(Path::new("inner.h")).is_err());
+    }
+
+    // The last of duplicate entries is used.
+    let mut tar = tar::Builder::new(Vec::new());
+    for (name, text) in [("main.c", "old\n"), ("main.c", "new\n")] {
+        let mut header = tar::Header::new_gnu();
+        header.set_size(text.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        tar.append_data(&mut header, name, text.as_bytes()).unwrap();
+    }
+    let archive = source::Archive::from_tar(&tar.into_inner().unwrap()[..]).unwrap();
+    let parser = Parser::builder().add_source(archive).build();
+    let node = parser.parse(Path::new("main.c")).unwrap();
+    assert_eq!(node.collect().0, "new\n");
+
+    // Entry name that is not UTF-8 is an error.
+    let mut tar = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_old();
+    header.as_old_mut().name[..6].copy_from_slice(b"bad\xff.h");
+    header.set_size(5);
+    header.set_mode(0o644);
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_cksum();
+    tar.append(&header, &b"text\n"[..]).unwrap();
+    let err = source::Archive::from_tar(&tar.into_inner().unwrap()[..])
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+}
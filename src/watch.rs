@@ -0,0 +1,142 @@
+//! Rebuilding the code when source files change.
+//!
+//! ```no_run
+//! use ocl_include::{source, watch::Watcher, Parser};
+//!
+//! let parser = Parser::builder()
+//!     .add_source(source::Fs::builder().include_dir("kernels").unwrap().build())
+//!     .build();
+//! Watcher::new(parser, "kernels/main.cl").run(|res| {
+//!     match res {
+//!         Ok((code, _index)) => println!("rebuilt, {} bytes", code.len()),
+//!         Err(e) => eprintln!("{}", e),
+//!     }
+//!     true
+//! });
+//! ```
+
+use crate::{error::Error, index::Index, parse::Parser, source::fs::Stamp};
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver},
+    thread,
+    time::Duration,
+};
+
+/// Polls modification times of all files the code depends on and parses it again when any of them changes.
+///
+/// Changed files are invalidated in the parser source before parsing, so cached content is not reused.
+pub struct Watcher {
+    parser: Parser,
+    main: PathBuf,
+    interval: Duration,
+    files: Option<Vec<(PathBuf, Option<Stamp>)>>,
+}
+
+impl Watcher {
+    pub fn new<P: AsRef<Path>>(parser: Parser, main: P) -> Self {
+        Self {
+            parser,
+            main: main.as_ref().to_path_buf(),
+            interval: Duration::from_millis(500),
+            files: None,
+        }
+    }
+
+    /// Interval between checks in `run` and `spawn`, 500 ms by default.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Files that are watched now.
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        self.files.iter().flatten().map(|(path, _)| path.as_path())
+    }
+
+    /// Parses the code on the first call, and on next calls only if any of the watched files changed.
+    ///
+    /// Returns `None` if nothing changed. If parsing fails, the files watched before are kept
+    /// together with the file where the error occured, and if some file is not found,
+    /// the paths where it could be created are watched too (see `Source::candidates`).
+    ///
+    /// This is a single check without waiting, so it could be driven by an external loop.
+    pub fn poll(&mut self) -> Option<Result<(String, Index), Error>> {
+        // Stamps are taken before parsing, so changes made during it are noticed next time.
+        let current = match &self.files {
+            Some(files) => {
+                let current = files
+                    .iter()
+                    .map(|(path, _)| (path.clone(), Stamp::of(path)))
+                    .collect::<Vec<_>>();
+                if current == *files {
+                    return None;
+                }
+                for ((path, stamp), (_, old)) in current.iter().zip(files.iter()) {
+                    if stamp != old {
+                        self.parser.source().invalidate(path);
+                    }
+                }
+                current
+            }
+            None => vec![(self.main.clone(), Stamp::of(&self.main))],
+        };
+
+        let res = self.parser.parse(&self.main);
+        let mut paths = match &res {
+            Ok(node) => node.files(),
+            Err(e) => {
+                let mut paths = current
+                    .iter()
+                    .map(|(path, _)| path.clone())
+                    .collect::<Vec<_>>();
+                if let Some(location) = e.location() {
+                    paths.push(location.path.clone());
+                    paths.extend(location.stack.iter().map(|(path, _)| path.clone()));
+                }
+                if let Error::NotFound { path, location } = e {
+                    // The file may be created later.
+                    let dir = location.as_ref().and_then(|l| l.path.parent());
+                    paths.extend(self.parser.source().candidates(path, dir));
+                }
+                paths
+            }
+        };
+        paths.sort();
+        paths.dedup();
+        self.files = Some(
+            paths
+                .into_iter()
+                .map(|path| {
+                    let stamp = match current.iter().find(|(p, _)| *p == path) {
+                        Some((_, stamp)) => *stamp,
+                        None => Stamp::of(&path),
+                    };
+                    (path, stamp)
+                })
+                .collect(),
+        );
+        Some(res.map(|node| node.collect()))
+    }
+
+    /// Polls for changes until `callback` returns `false`.
+    pub fn run<F: FnMut(Result<(String, Index), Error>) -> bool>(mut self, mut callback: F) {
+        loop {
+            if let Some(res) = self.poll() {
+                if !callback(res) {
+                    break;
+                }
+            }
+            thread::sleep(self.interval);
+        }
+    }
+
+    /// Runs the watcher in a separate thread and sends the results to the channel.
+    ///
+    /// The thread stops on the first change after the receiver is dropped.
+    pub fn spawn(self) -> Receiver<Result<(String, Index), Error>> {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || self.run(|res| sender.send(res).is_ok()));
+        receiver
+    }
+}
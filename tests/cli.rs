@@ -0,0 +1,133 @@
+use std::{
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Output, Stdio},
+};
+
+/// Creates empty directory that is unique for the test.
+fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("ocl-include-cli-{}-{}", name, std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(dir.join("include")).unwrap();
+    dir
+}
+
+fn run(dir: &Path, args: &[&str], stdin: Option<&str>) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_ocl-include"))
+        .args(args)
+        .current_dir(dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut input = child.stdin.take().unwrap();
+    input.write_all(stdin.unwrap_or("").as_bytes()).unwrap();
+    drop(input);
+    child.wait_with_output().unwrap()
+}
+
+fn stdout(output: &Output) -> &str {
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    std::str::from_utf8(&output.stdout).unwrap()
+}
+
+const MAIN: &str = "\
+#include <header.h>
+#if VALUE == 1
+value
+#endif
+#ifdef FLAG
+flag
+#endif
+";
+
+#[test]
+fn defines() {
+    let dir = test_dir("defines");
+    fs::write(dir.join("main.cl"), MAIN).unwrap();
+    fs::write(dir.join("include/header.h"), "header\n").unwrap();
+
+    let output = run(
+        &dir,
+        &["-I", "include", "-DVALUE", "-D", "FLAG", "main.cl"],
+        None,
+    );
+    assert_eq!(stdout(&output), "\nheader\n\nvalue\n\n\nflag\n\n");
+
+    let output = run(
+        &dir,
+        &["-Iinclude", "-D", "VALUE=2", "-U", "FLAG", "main.cl"],
+        None,
+    );
+    assert_eq!(stdout(&output), "\nheader\n\n\n\n\n\n\n");
+}
+
+#[test]
+fn stdin() {
+    let dir = test_dir("stdin");
+    fs::write(dir.join("include/header.h"), "header\n").unwrap();
+
+    let output = run(&dir, &["-I", "include", "-D", "VALUE", "-"], Some(MAIN));
+    assert_eq!(
+        stdout(&output),
+        "\nheader\n\nvalue\n\n#ifdef FLAG\nflag\n#endif\n"
+    );
+}
+
+#[test]
+fn depfile() {
+    let dir = test_dir("depfile");
+    fs::write(dir.join("main.cl"), MAIN).unwrap();
+    fs::write(dir.join("include/header.h"), "header\n").unwrap();
+
+    let output = run(
+        &dir,
+        &["-I", "include", "-o", "out.cl", "-MF", "out.d", "main.cl"],
+        None,
+    );
+    assert_eq!(stdout(&output), "");
+    assert!(fs::read_to_string(dir.join("out.cl"))
+        .unwrap()
+        .contains("header"));
+    let depfile = fs::read_to_string(dir.join("out.d")).unwrap();
+    assert!(depfile.starts_with("out.cl: "));
+    assert!(depfile.contains("main.cl"));
+    assert!(depfile.contains("include/header.h"));
+    assert!(depfile.contains("\ninclude/header.h:\n"));
+
+    let output = run(
+        &dir,
+        &["-I", "include", "-MF", "out.d", "-MT", "kernel", "main.cl"],
+        None,
+    );
+    stdout(&output);
+    assert!(fs::read_to_string(dir.join("out.d"))
+        .unwrap()
+        .starts_with("kernel: "));
+}
+
+#[test]
+fn errors() {
+    let dir = test_dir("errors");
+    fs::write(dir.join("main.cl"), MAIN).unwrap();
+
+    for args in [
+        &["main.cl"][..],
+        &["missing.cl"],
+        &["-I", "missing", "main.cl"],
+        &["--unknown", "main.cl"],
+        &["-D"],
+        &[],
+    ] {
+        let output = run(&dir, args, None);
+        assert_eq!(output.status.code(), Some(1), "{:?}", args);
+        assert!(output.stdout.is_empty());
+        assert!(String::from_utf8_lossy(&output.stderr).starts_with("ocl-include: "));
+    }
+}